@@ -1,11 +1,16 @@
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
 use std::sync::LazyLock;
 
 pub static ARGS: LazyLock<Args> = LazyLock::new(Args::parse);
 
 #[derive(Parser, Debug)]
-#[command(version, about)]
+#[allow(clippy::struct_excessive_bools)]
+#[command(version, about, args_conflicts_with_subcommands = true)]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// The packages to check
     pub packages: Vec<String>,
 
@@ -20,4 +25,235 @@ pub struct Args {
     /// Do not use the cache
     #[arg(short = 'c', long)]
     pub no_cache: bool,
+
+    /// Run fetch commands without the sandbox, for debugging
+    #[arg(long, global = true)]
+    pub no_sandbox: bool,
+
+    /// Seed for choosing which channels to check, to reproduce a run
+    #[arg(long)]
+    pub seed: Option<u64>,
+
+    /// Record every upstream response to a directory, as fixtures for --replay
+    #[arg(long, global = true, value_name = "DIR", conflicts_with = "replay")]
+    pub record: Option<PathBuf>,
+
+    /// Answer fetches only from fixtures recorded with --record, failing on any miss
+    #[arg(long, global = true, value_name = "DIR")]
+    pub replay: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// List the HEAD and tags of a git remote, like `git ls-remote`
+    LsRemote {
+        /// The remote's URL
+        url: String,
+    },
+
+    /// Compare two versions, printing '<', '=' or '>'
+    Cmp { a: String, b: String },
+
+    /// List channels whose versions haven't changed in a while, oldest first
+    Stale {
+        /// The packages to check (defaults to all)
+        packages: Vec<String>,
+
+        /// Minimum age in days
+        #[arg(short, long, default_value_t = 365)]
+        days: u64,
+
+        /// Output JSON instead of tab-separated plaintext
+        #[arg(short, long)]
+        json: bool,
+    },
+
+    /// Test channels against the sample upstream responses in their packages' configs
+    TestConfig {
+        /// The packages to test (defaults to all)
+        packages: Vec<String>,
+    },
+
+    /// Show each stage of a channel's fetch, to debug the version it arrives at
+    Explain {
+        /// The package to explain
+        package: String,
+
+        /// The channel to explain (defaults to all)
+        channel: Option<String>,
+
+        /// Show every line of each stage, instead of the last few
+        #[arg(short, long)]
+        all: bool,
+    },
+
+    /// Query a forge's API for a repository's latest release or commit
+    Forge {
+        query: ForgeQuery,
+
+        /// The repository's URL
+        upstream: String,
+    },
+
+    /// Query a package registry for a package's latest version
+    ///
+    /// These are nested under `registry`, since a top-level subcommand named after a registry
+    /// would shadow the package of the same name, like `go`.
+    Registry {
+        registry: Registry,
+        query: VersionQuery,
+
+        /// The package's URL on the registry
+        upstream: String,
+    },
+
+    /// List the versions of a package's archives in a directory listing from stdin
+    Listing {
+        /// The package's name, which archives are named after
+        name: String,
+
+        /// Output the filename, version, and modification time, tab-separated
+        #[arg(short, long)]
+        long: bool,
+    },
+
+    /// Sort versions from stdin in ascending order
+    Sort {
+        /// Sort in descending order
+        #[arg(short, long)]
+        reverse: bool,
+    },
+
+    /// Fetch a URL like `curl -fsL`, which fetch commands use while recording or replaying
+    Http {
+        url: String,
+
+        /// Write the body to a file instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Write the response's status line and headers to a file
+        #[arg(short = 'D', long)]
+        dump_header: Option<PathBuf>,
+    },
+
+    /// Inspect or manage .vat-cache
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CacheCommand {
+    /// List entries, oldest first, with their kind, size, creation time, and expiry
+    Ls {
+        /// Output JSON instead of tab-separated plaintext
+        #[arg(short, long)]
+        json: bool,
+    },
+
+    /// Summarize the number and size of entries
+    Stat,
+
+    /// Remove entries, or all of them if no filters are given
+    Purge {
+        /// Only remove expired entries
+        #[arg(short, long)]
+        expired: bool,
+
+        /// Only remove entries of this kind, like "git" or "curl"
+        #[arg(short, long)]
+        kind: Option<String>,
+
+        /// Only remove entries for these sources
+        sources: Vec<String>,
+    },
+
+    /// Print the path to a fresh cached response, or exit with 1 if there isn't one
+    Get { kind: String, source: String },
+
+    /// Print the path to a fresh cached response, or cache the output of a command first
+    ///
+    /// Concurrent fetches of the same kind and source are coalesced, so the command runs once
+    /// while the rest wait for its output.
+    Fetch {
+        kind: String,
+        source: String,
+
+        /// Ignore responses cached before this fetch started
+        #[arg(short, long)]
+        refresh: bool,
+
+        /// A file the command dumps the response's headers to, for its `ETag` and `Last-Modified`
+        #[arg(long)]
+        headers: Option<PathBuf>,
+
+        /// The command printing the response, which exits with its status if it fails
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+
+    /// Cache a response from stdin, printing the path to it
+    Put {
+        kind: String,
+        source: String,
+
+        /// A dump of the response's headers, for its `ETag` and `Last-Modified`
+        #[arg(long)]
+        headers: Option<PathBuf>,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ForgeQuery {
+    /// The tag of the latest stable release, falling back to tags
+    Release,
+
+    /// The tag of the latest release, including prereleases, falling back to tags
+    Unstable,
+
+    /// The latest commit on the default branch
+    Commit,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Registry {
+    /// `PyPI`, from a project's page
+    Pypi,
+
+    /// The npm registry
+    Npm,
+
+    /// The Go module proxy
+    Go,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum VersionQuery {
+    /// The latest stable release
+    Release,
+
+    /// The latest version, counting pre-releases
+    Unstable,
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::fs;
+
+    #[test]
+    fn packages_arent_subcommands() {
+        for entry in fs::read_dir("p").unwrap() {
+            let name = entry.unwrap().file_name().to_string_lossy().into_owned();
+            let args = Args::try_parse_from(["vat", &name])
+                .unwrap_or_else(|e| panic!("'vat {name}' doesn't parse: {e}"));
+
+            assert!(args.command.is_none(), "'{name}' parses as a subcommand");
+            assert_eq!(args.packages, [name]);
+        }
+    }
 }
@@ -0,0 +1,710 @@
+// backend/forge.rs
+//! Native release API backends for GitHub, GitLab, and Gitea/Forgejo
+//!
+//! Which API an upstream speaks is decided by the `forge` of the shortform it expands from, with
+//! github.com always speaking GitHub's.
+//!
+//! Responses are stored with their etags in the Vat state directory, and every request for a
+//! stored response is sent with `If-None-Match`. Unchanged repositories then get a 304, which
+//! doesn't count against GitHub's rate limit. Stored responses that go unused for a while are
+//! pruned at the start of a run.
+
+use std::env;
+use std::fmt::Write;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::utils::http::{self, HttpError};
+use crate::utils::ver::{self, ParsedVersion};
+use crate::{CONFIG, VAT_STATE};
+
+/// The most pages to request for a single listing
+const MAX_PAGES: usize = 10;
+
+/// How long a stored response is kept without being used
+const STORE_TTL: Duration = Duration::from_hours(30 * 24);
+
+#[derive(Error, Debug)]
+pub enum ForgeError {
+    #[error("no known forge for '{0}'")]
+    UnknownForge(String),
+
+    #[error("not a repository url: '{0}'")]
+    InvalidRepo(String),
+
+    #[error(transparent)]
+    Http(#[from] HttpError),
+
+    #[error("rate limited")]
+    RateLimited,
+
+    #[error("unexpected response")]
+    Json(#[from] serde_json::Error),
+
+    #[error("no releases or tags")]
+    NoReleases,
+
+    #[error("no commits")]
+    NoCommits,
+}
+
+/// # A forge API
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Forge {
+    GitHub,
+    GitLab,
+    #[serde(alias = "forgejo")]
+    Gitea,
+}
+
+impl Forge {
+    /// Find the forge an expanded upstream is hosted on, if any
+    pub fn detect(upstream: &str) -> Option<Self> {
+        if upstream.starts_with("https://github.com/") {
+            return Some(Self::GitHub);
+        }
+
+        let shortforms = &CONFIG
+            .get()
+            .expect("Config should be initialized")
+            .shortforms;
+        shortforms
+            .iter()
+            .find(|sf| upstream.starts_with(&sf.full))
+            .and_then(|sf| sf.forge)
+    }
+
+    /// Format a repository path from an upstream's path
+    ///
+    /// GitLab addresses projects by their URL-encoded full path, which may include subgroups.
+    fn repo(self, path: &str) -> Option<String> {
+        let segments = path.split('/').collect::<Vec<_>>();
+        if segments.iter().any(|s| s.is_empty()) {
+            return None;
+        }
+
+        match (self, segments.len()) {
+            (Self::GitHub | Self::Gitea, 2) => Some(path.to_string()),
+            (Self::GitLab, 2..) => Some(path.replace('/', "%2F")),
+            _ => None,
+        }
+    }
+
+    fn releases(self, repo: &str) -> String {
+        match self {
+            Self::GitHub | Self::Gitea => format!("/repos/{repo}/releases?{}", self.per_page()),
+            Self::GitLab => format!("/projects/{repo}/releases?{}", self.per_page()),
+        }
+    }
+
+    fn tags(self, repo: &str) -> String {
+        match self {
+            Self::GitHub | Self::Gitea => format!("/repos/{repo}/tags?{}", self.per_page()),
+            Self::GitLab => format!("/projects/{repo}/repository/tags?{}", self.per_page()),
+        }
+    }
+
+    fn latest_commit(self, repo: &str) -> String {
+        match self {
+            Self::GitHub => format!("/repos/{repo}/commits?per_page=1"),
+            Self::Gitea => format!("/repos/{repo}/commits?limit=1"),
+            Self::GitLab => format!("/projects/{repo}/repository/commits?per_page=1"),
+        }
+    }
+
+    /// The query for the largest page size the API allows
+    const fn per_page(self) -> &'static str {
+        match self {
+            Self::GitHub | Self::GitLab => "per_page=100",
+            Self::Gitea => "limit=50",
+        }
+    }
+}
+
+/// A release as returned by any of the forges
+#[derive(Debug, Deserialize)]
+struct Release {
+    tag_name: String,
+    #[serde(default)]
+    prerelease: bool,
+    #[serde(default)]
+    draft: bool,
+    /// GitLab's releases with a release date in the future
+    #[serde(default, rename = "upcoming_release")]
+    upcoming: bool,
+}
+
+impl Release {
+    const fn is_published(&self) -> bool {
+        !self.draft && !self.upcoming
+    }
+
+    const fn is_stable(&self) -> bool {
+        self.is_published() && !self.prerelease
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Tag {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Commit {
+    #[serde(alias = "id")]
+    sha: String,
+}
+
+/// A response stored for conditional requests
+#[derive(Debug, Serialize, Deserialize)]
+struct Stored {
+    etag: String,
+    link: Option<String>,
+    body: String,
+}
+
+/// # A forge API client
+#[derive(Debug)]
+pub struct Client {
+    forge: Forge,
+    api: String,
+    token: Option<String>,
+    /// Where responses are stored with their etags
+    store: PathBuf,
+}
+
+impl Client {
+    pub fn new<S: Into<String>>(
+        forge: Forge,
+        api: S,
+        token: Option<String>,
+        store: PathBuf,
+    ) -> Self {
+        Self {
+            forge,
+            api: api.into().trim_end_matches('/').to_string(),
+            token,
+            store,
+        }
+    }
+
+    /// A client for the forge an upstream is hosted on
+    ///
+    /// For GitHub, `GH_TOKEN` from the environment takes precedence over the token in the config.
+    pub fn for_upstream(upstream: &str) -> Result<Self, ForgeError> {
+        let forge =
+            Forge::detect(upstream).ok_or_else(|| ForgeError::UnknownForge(upstream.into()))?;
+        let (origin, _) = split_url(upstream)?;
+        let store = VAT_STATE.join("forge");
+
+        Ok(match forge {
+            Forge::GitHub => {
+                let config = &CONFIG.get().expect("Config should be initialized").github;
+                let token = env::var("GH_TOKEN")
+                    .ok()
+                    .filter(|t| !t.is_empty())
+                    .or_else(|| config.token.clone());
+
+                Self::new(forge, &config.api, token, store)
+            }
+            Forge::GitLab => Self::new(forge, format!("{origin}/api/v4"), None, store),
+            Forge::Gitea => Self::new(forge, format!("{origin}/api/v1"), None, store),
+        })
+    }
+
+    /// # The tag of the latest release that isn't a prerelease or draft
+    ///
+    /// Repositories without any such release fall back to their highest stable tag, trimmed like
+    /// `vtrim`.
+    pub fn latest_release(&self, upstream: &str) -> Result<String, ForgeError> {
+        self.latest(upstream, false)
+    }
+
+    /// # The tag of the latest release that isn't a draft, including prereleases
+    ///
+    /// Repositories without any such release fall back to their highest tag, trimmed like `vtrim`.
+    pub fn latest_unstable(&self, upstream: &str) -> Result<String, ForgeError> {
+        self.latest(upstream, true)
+    }
+
+    fn latest(&self, upstream: &str, unstable: bool) -> Result<String, ForgeError> {
+        let repo = self.repo(upstream)?;
+        let wanted = |r: &Release| if unstable { r.is_published() } else { r.is_stable() };
+
+        let releases = self.paginate::<Release>(&self.forge.releases(&repo), |page| {
+            page.iter().any(wanted)
+        })?;
+
+        if let Some(release) = releases.into_iter().find(wanted) {
+            return Ok(release.tag_name);
+        }
+
+        debug!("No matching releases for {upstream}, falling back to tags");
+        let tags = self.paginate::<Tag>(&self.forge.tags(&repo), |_| false)?;
+
+        // like `defgitrelease` and `defgitunstable`, tags are trimmed of the repository's name,
+        // then the ones still starting with a letter are dropped, along with pre-releases unless
+        // they're wanted
+        let (_, path) = split_url(upstream)?;
+        let name = path.rsplit('/').next().unwrap_or(path);
+        tags.into_iter()
+            .map(|t| ver::vtrim(&t.name, name))
+            .filter(|v| v.starts_with(|c: char| !c.is_ascii_alphabetic()))
+            .map(|v| (ParsedVersion::parse(&v), v))
+            .filter(|(v, _)| unstable || !v.is_prerelease())
+            .max()
+            .map(|(_, v)| v)
+            .ok_or(ForgeError::NoReleases)
+    }
+
+    /// # The latest commit on the default branch
+    pub fn latest_commit(&self, upstream: &str) -> Result<String, ForgeError> {
+        let repo = self.repo(upstream)?;
+        let url = format!("{}{}", self.api, self.forge.latest_commit(&repo));
+        let (body, _) = self.get(&url)?;
+
+        serde_json::from_str::<Vec<Commit>>(&body)?
+            .into_iter()
+            .next()
+            .map(|c| c.sha)
+            .ok_or(ForgeError::NoCommits)
+    }
+
+    fn repo(&self, upstream: &str) -> Result<String, ForgeError> {
+        let (_, path) = split_url(upstream)?;
+        self.forge
+            .repo(path)
+            .ok_or_else(|| ForgeError::InvalidRepo(upstream.into()))
+    }
+
+    /// Request pages of a listing until `done` returns true for a page or there are no more
+    fn paginate<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        mut done: impl FnMut(&[T]) -> bool,
+    ) -> Result<Vec<T>, ForgeError> {
+        let mut items = vec![];
+        let mut url = Some(format!("{}{path}", self.api));
+
+        for _ in 0..MAX_PAGES {
+            let Some(u) = url.take() else {
+                break;
+            };
+
+            let (body, link) = self.get(&u)?;
+            let page = serde_json::from_str::<Vec<T>>(&body)?;
+            let stop = done(&page);
+            items.extend(page);
+
+            if stop {
+                break;
+            }
+            url = link.as_deref().and_then(next_link);
+        }
+
+        Ok(items)
+    }
+
+    /// Send a conditional GET, returning the body and `Link` header
+    fn get(&self, url: &str) -> Result<(String, Option<String>), ForgeError> {
+        let path = self.store.join(hash(url) + ".json");
+        let stored = fs::read_to_string(&path)
+            .ok()
+            .and_then(|s| serde_json::from_str::<Stored>(&s).ok());
+
+        let auth = self.token.as_ref().map(|t| format!("Bearer {t}"));
+        let mut headers = if self.forge == Forge::GitHub {
+            vec![
+                ("Accept", "application/vnd.github+json"),
+                ("X-GitHub-Api-Version", "2022-11-28"),
+            ]
+        } else {
+            vec![("Accept", "application/json")]
+        };
+        if let Some(auth) = &auth {
+            headers.push(("Authorization", auth));
+        }
+        if let Some(stored) = &stored {
+            headers.push(("If-None-Match", &stored.etag));
+        }
+
+        let res = http::get(url, &headers)?;
+
+        if res.status == 304
+            && let Some(stored) = stored
+        {
+            trace!("Not modified: {url}");
+
+            // keep it from being pruned while it's still in use
+            let _ = File::options()
+                .write(true)
+                .open(&path)
+                .and_then(|f| f.set_modified(SystemTime::now()));
+            return Ok((stored.body, stored.link));
+        }
+
+        if matches!(res.status, 403 | 429) && res.header("x-ratelimit-remaining") == Some("0") {
+            return Err(ForgeError::RateLimited);
+        }
+
+        let res = res.error_for_status()?;
+        let body = String::from_utf8_lossy(&res.body).to_string();
+        let link = res.header("link").map(ToString::to_string);
+
+        if let Some(etag) = res.header("etag") {
+            let stored = Stored {
+                etag: etag.to_string(),
+                link: link.clone(),
+                body: body.clone(),
+            };
+
+            // failing to store a response only costs a full request next time
+            if let Err(e) = fs::create_dir_all(&self.store)
+                .and_then(|()| fs::write(&path, serde_json::to_string(&stored)?))
+            {
+                warn!("Failed to store response for {url}: {e}");
+            }
+        }
+
+        Ok((body, link))
+    }
+}
+
+/// # The tag of an upstream's latest stable release
+pub fn latest_release(upstream: &str) -> Result<String, ForgeError> {
+    Client::for_upstream(upstream)?.latest_release(upstream)
+}
+
+/// # The tag of an upstream's latest release, including prereleases
+pub fn latest_unstable(upstream: &str) -> Result<String, ForgeError> {
+    Client::for_upstream(upstream)?.latest_unstable(upstream)
+}
+
+/// # The latest commit on an upstream's default branch
+pub fn latest_commit(upstream: &str) -> Result<String, ForgeError> {
+    Client::for_upstream(upstream)?.latest_commit(upstream)
+}
+
+/// # Remove stored responses that haven't been used in a while
+pub fn prune_store() {
+    prune(&VAT_STATE.join("forge"), STORE_TTL);
+}
+
+/// Remove stored responses unused for `ttl`, and any named differently, like by older versions
+fn prune(store: &Path, ttl: Duration) {
+    let Ok(dir) = fs::read_dir(store) else {
+        return;
+    };
+
+    let now = SystemTime::now();
+    for file in dir.filter_map(Result::ok) {
+        let path = file.path();
+        let named = path
+            .file_stem()
+            .is_some_and(|stem| stem.len() == 64 && path.extension().is_some_and(|e| e == "json"));
+        let unused = file
+            .metadata()
+            .and_then(|m| m.modified())
+            .is_ok_and(|modified| now.duration_since(modified).unwrap_or_default() >= ttl);
+
+        if !named || unused {
+            trace!("Pruning stored response {}", path.display());
+            let _ = fs::remove_file(&path);
+        }
+    }
+}
+
+/// Split a URL into its origin and path, without a trailing slash or `.git`
+fn split_url(upstream: &str) -> Result<(&str, &str), ForgeError> {
+    let invalid = || ForgeError::InvalidRepo(upstream.into());
+
+    let scheme_end = upstream.find("://").ok_or_else(invalid)? + 3;
+    let path_start = upstream[scheme_end..]
+        .find('/')
+        .map(|i| scheme_end + i)
+        .ok_or_else(invalid)?;
+
+    let path = upstream[path_start..]
+        .trim_matches('/')
+        .trim_end_matches(".git");
+
+    Ok((&upstream[..path_start], path))
+}
+
+/// Find the `rel="next"` URL in a `Link` header
+fn next_link(link: &str) -> Option<String> {
+    link.split(',').find_map(|part| {
+        let (url, params) = part.split_once(';')?;
+        params
+            .split(';')
+            .any(|p| p.trim() == r#"rel="next""#)
+            .then(|| {
+                url.trim()
+                    .trim_start_matches('<')
+                    .trim_end_matches('>')
+                    .to_string()
+            })
+    })
+}
+
+/// The SHA-256 of a string in hex, which stays the same across builds, unlike `DefaultHasher`
+fn hash(s: &str) -> String {
+    Sha256::digest(s).iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{b:02x}");
+        s
+    })
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    /// Requests seen by the mock server, as (path, if-none-match)
+    type Seen = Arc<Mutex<Vec<(String, Option<String>)>>>;
+
+    /// Serve canned forge API responses on a local port
+    fn mock() -> (String, Seen) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let seen = Seen::default();
+
+        let (b, s) = (base.clone(), seen.clone());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+                let mut request = String::new();
+                reader.read_line(&mut request).unwrap();
+                let path = request.split(' ').nth(1).unwrap().to_string();
+
+                let mut etag = None;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((k, v)) = header.split_once(':')
+                        && k.eq_ignore_ascii_case("if-none-match")
+                    {
+                        etag = Some(v.trim().to_string());
+                    }
+                }
+                s.lock().unwrap().push((path.clone(), etag.clone()));
+
+                let (status, link, body) = match path.as_str() {
+                    "/repos/o/r/releases?per_page=100" if etag.as_deref() == Some("\"r1\"") => {
+                        ("304 Not Modified", None, String::new())
+                    }
+                    "/repos/o/r/releases?per_page=100" => (
+                        "200 OK",
+                        Some(format!(
+                            "<{b}/repos/o/r/releases?per_page=100&page=2>; rel=\"next\""
+                        )),
+                        r#"[{"tag_name":"v2.0-rc1","prerelease":true,"draft":false},
+                            {"tag_name":"v1.9","prerelease":false,"draft":true}]"#
+                            .to_string(),
+                    ),
+                    "/repos/o/r/releases?per_page=100&page=2" => (
+                        "200 OK",
+                        None,
+                        r#"[{"tag_name":"v1.8","prerelease":false,"draft":false}]"#.to_string(),
+                    ),
+                    "/repos/o/t/releases?per_page=100" | "/repos/o/tool/releases?per_page=100" => {
+                        ("200 OK", None, "[]".to_string())
+                    }
+                    "/repos/o/t/tags?per_page=100" => (
+                        "200 OK",
+                        None,
+                        r#"[{"name":"v1.10"},{"name":"v2.0-beta1"},{"name":"v1.9"},{"name":"nightly"}]"#
+                            .to_string(),
+                    ),
+                    "/repos/o/tool/tags?per_page=100" => (
+                        "200 OK",
+                        None,
+                        r#"[{"name":"Tool-1.2"},{"name":"release-1.3"},{"name":"1_4_0"},
+                            {"name":"tool_2_0_rc1"},{"name":"nightly"}]"#
+                            .to_string(),
+                    ),
+                    "/api/v4/projects/g%2Fs%2Fp/releases?per_page=100" => (
+                        "200 OK",
+                        None,
+                        r#"[{"tag_name":"3.0","upcoming_release":true},
+                            {"tag_name":"2.9","upcoming_release":false}]"#
+                            .to_string(),
+                    ),
+                    "/api/v4/projects/g%2Fs%2Fp/repository/commits?per_page=1" => {
+                        ("200 OK", None, r#"[{"id":"abc"}]"#.to_string())
+                    }
+                    "/api/v1/repos/o/r/releases?limit=50" => (
+                        "200 OK",
+                        None,
+                        r#"[{"tag_name":"v5.0-rc1","prerelease":true,"draft":false},
+                            {"tag_name":"v4.2","prerelease":false,"draft":false}]"#
+                            .to_string(),
+                    ),
+                    _ => ("404 Not Found", None, "{}".to_string()),
+                };
+
+                let mut res = format!(
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n",
+                    body.len()
+                );
+                if status.starts_with("200") {
+                    res.push_str("ETag: \"r1\"\r\n");
+                }
+                if let Some(link) = link {
+                    res = res + "Link: " + &link + "\r\n";
+                }
+                res.push_str("\r\n");
+                res.push_str(&body);
+                stream.write_all(res.as_bytes()).unwrap();
+            }
+        });
+
+        (base, seen)
+    }
+
+    fn store(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("vat-forge-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        path
+    }
+
+    #[test]
+    fn releases_paginate_and_skip_prereleases() {
+        let (base, seen) = mock();
+        let client = Client::new(Forge::GitHub, base, None, store("releases"));
+
+        assert_eq!(
+            client.latest_release("https://github.com/o/r").unwrap(),
+            "v1.8"
+        );
+        assert_eq!(seen.lock().unwrap().len(), 2);
+
+        // the stored etag turns the first page into a 304, and the stored link is still followed
+        assert_eq!(
+            client.latest_release("https://github.com/o/r.git").unwrap(),
+            "v1.8"
+        );
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 4);
+        assert_eq!(seen[2].1.as_deref(), Some("\"r1\""));
+    }
+
+    #[test]
+    fn unstable_includes_prereleases() {
+        let (base, _) = mock();
+        let client = Client::new(Forge::GitHub, base.clone(), None, store("unstable"));
+
+        // drafts are still skipped
+        assert_eq!(
+            client.latest_unstable("https://github.com/o/r").unwrap(),
+            "v2.0-rc1"
+        );
+        assert_eq!(
+            client.latest_unstable("https://github.com/o/t").unwrap(),
+            "2.0-beta1"
+        );
+
+        let gitlab = Client::new(Forge::GitLab, format!("{base}/api/v4"), None, store("gl-unstable"));
+        assert_eq!(gitlab.latest_unstable(&format!("{base}/g/s/p")).unwrap(), "2.9");
+    }
+
+    #[test]
+    fn prune_unused() {
+        let store = store("prune");
+        fs::create_dir_all(&store).unwrap();
+
+        let fresh = store.join(hash("https://a/fresh") + ".json");
+        let unused = store.join(hash("https://a/unused") + ".json");
+        let legacy = store.join("0123456789abcdef.json");
+        for path in [&fresh, &unused, &legacy] {
+            fs::write(path, "{}").unwrap();
+        }
+        let old = SystemTime::now() - Duration::from_hours(48);
+        File::options().write(true).open(&unused).unwrap().set_modified(old).unwrap();
+
+        prune(&store, Duration::from_hours(24));
+        assert!(fresh.exists());
+        assert!(!unused.exists());
+        assert!(!legacy.exists());
+
+        let _ = fs::remove_dir_all(&store);
+    }
+
+    #[test]
+    fn tags_fallback() {
+        let (base, _) = mock();
+        let client = Client::new(Forge::GitHub, base, None, store("tags"));
+
+        assert_eq!(
+            client.latest_release("https://github.com/o/t").unwrap(),
+            "1.10"
+        );
+        assert_eq!(
+            client.latest_release("https://github.com/o/tool").unwrap(),
+            "1.4.0"
+        );
+        assert!(matches!(
+            client.latest_release("https://github.com/o/missing"),
+            Err(ForgeError::Http(HttpError::Status(404)))
+        ));
+    }
+
+    #[test]
+    fn gitlab_and_gitea() {
+        let (base, _) = mock();
+
+        let gitlab = Client::new(Forge::GitLab, format!("{base}/api/v4"), None, store("gl"));
+        let upstream = format!("{base}/g/s/p");
+        assert_eq!(gitlab.latest_release(&upstream).unwrap(), "2.9");
+        assert_eq!(gitlab.latest_commit(&upstream).unwrap(), "abc");
+
+        let gitea = Client::new(Forge::Gitea, format!("{base}/api/v1"), None, store("gt"));
+        assert_eq!(
+            gitea.latest_release(&format!("{base}/o/r")).unwrap(),
+            "v4.2"
+        );
+        assert!(matches!(
+            gitea.latest_release(&format!("{base}/g/s/p")),
+            Err(ForgeError::InvalidRepo(_))
+        ));
+    }
+
+    #[test]
+    fn parse_urls() {
+        assert_eq!(
+            split_url("https://github.com/tmux/tmux.git/").unwrap(),
+            ("https://github.com", "tmux/tmux")
+        );
+        assert_eq!(Forge::GitHub.repo("tmux"), None);
+        assert_eq!(Forge::GitLab.repo("a/b/c"), Some("a%2Fb%2Fc".into()));
+
+        assert_eq!(
+            Forge::detect("https://codeberg.org/dnkl/foot"),
+            Some(Forge::Gitea)
+        );
+        assert_eq!(Forge::detect("https://git.kernel.org/pub/scm/x"), None);
+
+        assert_eq!(
+            next_link(r#"<https://x/?page=2>; rel="next", <https://x/?page=5>; rel="last""#),
+            Some("https://x/?page=2".into())
+        );
+        assert_eq!(next_link(r#"<https://x/?page=1>; rel="prev""#), None);
+    }
+}
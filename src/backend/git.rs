@@ -0,0 +1,437 @@
+// backend/git.rs
+//! Native git ref listing over the smart HTTP protocol
+//!
+//! Protocol v2 is preferred since `ls-refs` lets us ask for only the refs we care about. Servers
+//! that only speak v0 or the dumb protocol are handled too, but they always send every ref.
+
+use std::fmt::Write;
+
+use thiserror::Error;
+
+use crate::NO_CACHE;
+use crate::utils::cache::CACHE;
+use crate::utils::http::{self, HttpError};
+
+const ADVERTISEMENT: &str = "application/x-git-upload-pack-advertisement";
+
+#[derive(Error, Debug)]
+pub enum GitError {
+    #[error("unsupported url '{0}' (only http and https are supported)")]
+    UnsupportedScheme(String),
+
+    #[error(transparent)]
+    Http(#[from] HttpError),
+
+    #[error("malformed pkt-line")]
+    MalformedPktLine,
+
+    #[error("protocol error: {0}")]
+    Protocol(String),
+
+    #[error("no HEAD advertised")]
+    MissingHead,
+}
+
+/// # A single ref advertised by a remote
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ref {
+    pub oid: String,
+    pub name: String,
+    /// The object an annotated tag points to
+    pub peeled: Option<String>,
+    /// The ref a symbolic ref (usually HEAD) points to
+    pub symref_target: Option<String>,
+}
+
+impl Ref {
+    /// The commit this ref resolves to, peeling annotated tags
+    pub fn commit(&self) -> &str {
+        self.peeled.as_deref().unwrap_or(&self.oid)
+    }
+}
+
+/// # The refs advertised by a remote, in the order the remote sent them
+#[derive(Debug, Clone, Default)]
+pub struct Refs(pub Vec<Ref>);
+
+impl Refs {
+    pub fn head(&self) -> Option<&Ref> {
+        self.0.iter().find(|r| r.name == "HEAD")
+    }
+
+    /// Format the refs like `git ls-remote`, with annotated tags peeled
+    pub fn to_ls_remote(&self) -> String {
+        let mut s = String::new();
+        for r in &self.0 {
+            let _ = writeln!(s, "{}\t{}", r.commit(), r.name);
+        }
+        s
+    }
+
+    /// Parse refs formatted like `git ls-remote`
+    pub fn from_ls_remote(s: &str) -> Self {
+        let refs = s
+            .lines()
+            .filter_map(|l| l.split_once('\t'))
+            .map(|(oid, name)| Ref {
+                oid: oid.to_string(),
+                name: name.to_string(),
+                peeled: None,
+                symref_target: None,
+            })
+            .collect();
+        Self(refs)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Pkt<'a> {
+    Flush,
+    Delim,
+    ResponseEnd,
+    Data(&'a str),
+}
+
+fn pkt(s: &str) -> String {
+    format!("{:04x}{s}", s.len() + 4)
+}
+
+fn parse_pkts(buf: &[u8]) -> Result<Vec<Pkt<'_>>, GitError> {
+    let mut pkts = vec![];
+    let mut rest = buf;
+
+    while !rest.is_empty() {
+        let len = rest
+            .get(..4)
+            .and_then(|l| std::str::from_utf8(l).ok())
+            .and_then(|l| usize::from_str_radix(l, 16).ok())
+            .ok_or(GitError::MalformedPktLine)?;
+
+        let (line, tail) = match len {
+            0 => (Pkt::Flush, &rest[4..]),
+            1 => (Pkt::Delim, &rest[4..]),
+            2 => (Pkt::ResponseEnd, &rest[4..]),
+            3 => return Err(GitError::MalformedPktLine),
+            n => {
+                let data = rest.get(4..n).ok_or(GitError::MalformedPktLine)?;
+                let data = std::str::from_utf8(data).map_err(|_| GitError::MalformedPktLine)?;
+                (Pkt::Data(data.trim_end_matches('\n')), &rest[n..])
+            }
+        };
+
+        pkts.push(line);
+        rest = tail;
+    }
+
+    Ok(pkts)
+}
+
+fn is_oid(s: &str) -> bool {
+    matches!(s.len(), 40 | 64) && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn matches_prefix(name: &str, prefixes: &[&str]) -> bool {
+    prefixes.is_empty() || prefixes.iter().any(|p| name.starts_with(p))
+}
+
+/// # HEAD and the tags on a remote, through the cache shared with `gr` and `githead`
+///
+/// Concurrent listings of the same remote, in-process or from fetch commands, make one request.
+/// Annotated tags come back already peeled.
+pub fn cached_refs(url: &str) -> Result<Refs, GitError> {
+    let listing = CACHE.fetch_with("git", url, *NO_CACHE, || {
+        let refs = ls_refs(url, &["HEAD", "refs/tags/"])?;
+        Ok::<_, GitError>((refs.to_ls_remote().into_bytes(), None))
+    })?;
+    Ok(Refs::from_ls_remote(&String::from_utf8_lossy(&listing)))
+}
+
+/// # List refs on a remote
+///
+/// Only refs starting with one of `prefixes` are returned. An empty slice returns every ref.
+pub fn ls_refs(url: &str, prefixes: &[&str]) -> Result<Refs, GitError> {
+    let base = url.trim_end_matches('/');
+    if !base.starts_with("https://") && !base.starts_with("http://") {
+        return Err(GitError::UnsupportedScheme(url.to_string()));
+    }
+
+    let res = http::get(
+        &format!("{base}/info/refs?service=git-upload-pack"),
+        &[("Git-Protocol", "version=2")],
+    )?
+    .error_for_status()?;
+
+    let smart = res
+        .header("content-type")
+        .is_some_and(|ct| ct.starts_with(ADVERTISEMENT));
+    if !smart {
+        debug!("Falling back to the dumb protocol for {base}");
+        return parse_dumb(base, &String::from_utf8_lossy(&res.body), prefixes);
+    }
+
+    let pkts = parse_pkts(&res.body)?;
+    let mut lines = pkts.iter().filter_map(|p| match p {
+        Pkt::Data(d) if !d.starts_with("# service=") => Some(*d),
+        _ => None,
+    });
+
+    if lines.next() == Some("version 2") {
+        trace!("Using protocol v2 for {base}");
+        return ls_refs_v2(base, prefixes);
+    }
+
+    trace!("Using protocol v0 for {base}");
+    parse_v0(&pkts, prefixes)
+}
+
+fn ls_refs_v2(base: &str, prefixes: &[&str]) -> Result<Refs, GitError> {
+    let mut body = pkt("command=ls-refs\n");
+    body.push_str("0001");
+    body.push_str(&pkt("peel\n"));
+    body.push_str(&pkt("symrefs\n"));
+    for prefix in prefixes {
+        body.push_str(&pkt(&format!("ref-prefix {prefix}\n")));
+    }
+    body.push_str("0000");
+
+    let res = http::post(
+        &format!("{base}/git-upload-pack"),
+        &[
+            ("Git-Protocol", "version=2"),
+            ("Content-Type", "application/x-git-upload-pack-request"),
+            ("Accept", "application/x-git-upload-pack-result"),
+        ],
+        body.as_bytes(),
+    )?
+    .error_for_status()?;
+
+    parse_v2(&parse_pkts(&res.body)?)
+}
+
+fn parse_v2(pkts: &[Pkt]) -> Result<Refs, GitError> {
+    let mut refs = vec![];
+
+    for pkt in pkts {
+        let Pkt::Data(line) = pkt else { break };
+
+        if let Some(msg) = line.strip_prefix("ERR ") {
+            return Err(GitError::Protocol(msg.to_string()));
+        }
+
+        let mut fields = line.split(' ');
+        let (Some(oid), Some(name)) = (fields.next(), fields.next()) else {
+            return Err(GitError::Protocol(format!(
+                "unexpected ls-refs line '{line}'"
+            )));
+        };
+
+        let mut r = Ref {
+            oid: oid.to_string(),
+            name: name.to_string(),
+            peeled: None,
+            symref_target: None,
+        };
+
+        for attr in fields {
+            if let Some(p) = attr.strip_prefix("peeled:") {
+                r.peeled = Some(p.to_string());
+            } else if let Some(t) = attr.strip_prefix("symref-target:") {
+                r.symref_target = Some(t.to_string());
+            }
+        }
+
+        refs.push(r);
+    }
+
+    Ok(Refs(refs))
+}
+
+fn parse_v0(pkts: &[Pkt], prefixes: &[&str]) -> Result<Refs, GitError> {
+    let mut refs: Vec<Ref> = vec![];
+    let mut head_target = None;
+
+    for pkt in pkts {
+        let Pkt::Data(line) = pkt else { continue };
+        if line.starts_with("# service=") {
+            continue;
+        }
+
+        if let Some(msg) = line.strip_prefix("ERR ") {
+            return Err(GitError::Protocol(msg.to_string()));
+        }
+
+        let (line, caps) = line.split_once('\0').unwrap_or((line, ""));
+        if let Some(target) = caps.split(' ').find_map(|c| c.strip_prefix("symref=HEAD:")) {
+            head_target = Some(target.to_string());
+        }
+
+        let Some((oid, name)) = line.split_once(' ') else {
+            return Err(GitError::Protocol(format!(
+                "unexpected advertisement line '{line}'"
+            )));
+        };
+
+        // empty repositories advertise only their capabilities
+        if name == "capabilities^{}" {
+            continue;
+        }
+
+        if let Some(name) = name.strip_suffix("^{}") {
+            if let Some(r) = refs.iter_mut().rev().find(|r| r.name == name) {
+                r.peeled = Some(oid.to_string());
+            }
+            continue;
+        }
+
+        refs.push(Ref {
+            oid: oid.to_string(),
+            name: name.to_string(),
+            peeled: None,
+            symref_target: None,
+        });
+    }
+
+    if let Some(head) = refs.iter_mut().find(|r| r.name == "HEAD") {
+        head.symref_target = head_target;
+    }
+
+    refs.retain(|r| matches_prefix(&r.name, prefixes));
+    Ok(Refs(refs))
+}
+
+fn parse_dumb(base: &str, info_refs: &str, prefixes: &[&str]) -> Result<Refs, GitError> {
+    let mut refs: Vec<Ref> = vec![];
+
+    for line in info_refs.lines().filter(|l| !l.is_empty()) {
+        let Some((oid, name)) = line.split_once('\t') else {
+            return Err(GitError::Protocol(format!(
+                "unexpected info/refs line '{line}'"
+            )));
+        };
+
+        if let Some(name) = name.strip_suffix("^{}") {
+            if let Some(r) = refs.iter_mut().rev().find(|r| r.name == name) {
+                r.peeled = Some(oid.to_string());
+            }
+            continue;
+        }
+
+        refs.push(Ref {
+            oid: oid.to_string(),
+            name: name.to_string(),
+            peeled: None,
+            symref_target: None,
+        });
+    }
+
+    // the dumb protocol doesn't advertise HEAD, so resolve it ourselves
+    if matches_prefix("HEAD", prefixes) {
+        let head = http::get(&format!("{base}/HEAD"), &[])?
+            .error_for_status()?
+            .body;
+        let head = String::from_utf8_lossy(&head).trim().to_string();
+
+        let (oid, symref_target) = head.strip_prefix("ref: ").map_or_else(
+            || (is_oid(&head).then(|| head.clone()), None),
+            |target| {
+                let oid = refs
+                    .iter()
+                    .find(|r| r.name == target)
+                    .map(|r| r.oid.clone());
+                (oid, Some(target.to_string()))
+            },
+        );
+
+        if let Some(oid) = oid {
+            refs.insert(
+                0,
+                Ref {
+                    oid,
+                    name: "HEAD".into(),
+                    peeled: None,
+                    symref_target,
+                },
+            );
+        }
+    }
+
+    refs.retain(|r| matches_prefix(&r.name, prefixes));
+    Ok(Refs(refs))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const OID_A: &str = "1111111111111111111111111111111111111111";
+    const OID_B: &str = "2222222222222222222222222222222222222222";
+    const OID_C: &str = "3333333333333333333333333333333333333333";
+
+    #[test]
+    fn pkt_roundtrip() {
+        let buf = format!("{}0001{}0000", pkt("command=ls-refs\n"), pkt("peel\n"));
+        let pkts = parse_pkts(buf.as_bytes()).unwrap();
+
+        assert_eq!(
+            pkts,
+            [
+                Pkt::Data("command=ls-refs"),
+                Pkt::Delim,
+                Pkt::Data("peel"),
+                Pkt::Flush
+            ]
+        );
+        assert!(parse_pkts(b"00").is_err());
+        assert!(parse_pkts(b"0009ab").is_err());
+    }
+
+    #[test]
+    fn v2_peels_tags() {
+        let buf = format!(
+            "{}{}{}0000",
+            pkt(&format!("{OID_A} HEAD symref-target:refs/heads/main\n")),
+            pkt(&format!("{OID_B} refs/tags/v1.0 peeled:{OID_C}\n")),
+            pkt(&format!("{OID_A} refs/tags/v1.1\n")),
+        );
+        let refs = parse_v2(&parse_pkts(buf.as_bytes()).unwrap()).unwrap();
+
+        let head = refs.head().unwrap();
+        assert_eq!(head.oid, OID_A);
+        assert_eq!(head.symref_target.as_deref(), Some("refs/heads/main"));
+
+        assert_eq!(
+            refs.to_ls_remote(),
+            format!("{OID_A}\tHEAD\n{OID_C}\trefs/tags/v1.0\n{OID_A}\trefs/tags/v1.1\n")
+        );
+    }
+
+    #[test]
+    fn v0_filters_and_peels() {
+        let buf = format!(
+            "001e# service=git-upload-pack\n0000{}{}{}{}0000",
+            pkt(&format!(
+                "{OID_A} HEAD\0multi_ack symref=HEAD:refs/heads/main\n"
+            )),
+            pkt(&format!("{OID_A} refs/heads/main\n")),
+            pkt(&format!("{OID_B} refs/tags/v1.0\n")),
+            pkt(&format!("{OID_C} refs/tags/v1.0^{{}}\n")),
+        );
+        let refs = parse_v0(
+            &parse_pkts(buf.as_bytes()).unwrap(),
+            &["HEAD", "refs/tags/"],
+        )
+        .unwrap();
+
+        assert_eq!(refs.0.len(), 2);
+        assert_eq!(
+            refs.head().unwrap().symref_target.as_deref(),
+            Some("refs/heads/main")
+        );
+        assert_eq!(
+            refs.to_ls_remote(),
+            format!("{OID_A}\tHEAD\n{OID_C}\trefs/tags/v1.0\n")
+        );
+    }
+}
@@ -0,0 +1,162 @@
+// backend/go.rs
+//! Native Go module proxy backend
+//!
+//! Versions come from `@v/list`. Modules without tagged versions fall back to `@latest`, which
+//! is only used if it isn't a pseudo-version, since those are commits rather than releases.
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::CONFIG;
+use crate::utils::http::{self, HttpError};
+use crate::utils::ver;
+
+/// The public proxy, which upstreams may point to even when a mirror is configured
+const PUBLIC: &str = "https://proxy.golang.org";
+
+#[derive(Error, Debug)]
+pub enum GoError {
+    #[error("not a go module url: '{0}'")]
+    InvalidModule(String),
+
+    #[error(transparent)]
+    Http(#[from] HttpError),
+
+    #[error("unexpected response")]
+    Json(#[from] serde_json::Error),
+
+    #[error("no tagged versions")]
+    NoVersions,
+}
+
+#[derive(Debug, Deserialize)]
+struct Info {
+    #[serde(rename = "Version")]
+    version: String,
+}
+
+/// The configured proxy, without a trailing slash
+fn proxy() -> &'static str {
+    let config = CONFIG.get().expect("Config should be initialized");
+    config.registries.go.trim_end_matches('/')
+}
+
+/// Extract the module path from an upstream on the public or configured proxy
+pub fn module(upstream: &str) -> Option<&str> {
+    [PUBLIC, proxy()]
+        .into_iter()
+        .find_map(|base| upstream.strip_prefix(base)?.strip_prefix('/'))
+        .map(|path| path.trim_end_matches('/'))
+        .filter(|path| path.contains('/'))
+}
+
+/// # Fetch the latest version of a Go module
+///
+/// With `unstable`, pre-releases are considered too.
+pub fn latest(upstream: &str, unstable: bool) -> Result<String, GoError> {
+    let module = module(upstream).ok_or_else(|| GoError::InvalidModule(upstream.to_string()))?;
+    latest_from(proxy(), module, unstable)
+}
+
+fn latest_from(proxy: &str, module: &str, unstable: bool) -> Result<String, GoError> {
+    let base = format!("{proxy}/{}", escape(module));
+
+    let list = http::get_cached("go", &format!("{base}/@v/list"), &[])?;
+    let list = String::from_utf8_lossy(&list);
+
+    if let Some(version) = select(list.lines(), unstable) {
+        return Ok(version);
+    }
+
+    debug!("No tagged versions for {module}, falling back to @latest");
+    let info = http::get_cached("go", &format!("{base}/@latest"), &[])?;
+    let info = serde_json::from_slice::<Info>(&info)?;
+
+    select([info.version.as_str()], unstable).ok_or(GoError::NoVersions)
+}
+
+/// Pick the highest version, without build metadata like "+incompatible"
+fn select<'a>(versions: impl IntoIterator<Item = &'a str>, unstable: bool) -> Option<String> {
+    let mut versions = versions
+        .into_iter()
+        .map(|v| v.trim().split('+').next().unwrap_or_default())
+        .filter(|v| v.starts_with('v') && !is_pseudo(v))
+        .filter(|v| unstable || !v.contains('-'))
+        .collect::<Vec<_>>();
+
+    ver::sort(&mut versions);
+    versions.pop().map(ToString::to_string)
+}
+
+/// Whether a version is a pseudo-version, like "v0.0.0-20191109021931-daa7c04131f5"
+fn is_pseudo(version: &str) -> bool {
+    let mut parts = version.rsplit('-');
+    let (Some(rev), Some(time)) = (parts.next(), parts.next()) else {
+        return false;
+    };
+
+    let time = time.rsplit('.').next().unwrap_or_default();
+    rev.len() == 12
+        && rev.bytes().all(|b| b.is_ascii_hexdigit())
+        && time.len() == 14
+        && time.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Escape uppercase letters as the proxy protocol requires, like `!burnt!sushi` for `BurntSushi`
+fn escape(module: &str) -> String {
+    let mut escaped = String::with_capacity(module.len());
+    for c in module.chars() {
+        if c.is_ascii_uppercase() {
+            escaped.push('!');
+            escaped.push(c.to_ascii_lowercase());
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn select_versions() {
+        let list = [
+            "v1.9.0",
+            "v1.10.0",
+            "v1.11.0-rc.1",
+            "v2.0.0+incompatible",
+            "bogus",
+        ];
+        assert_eq!(select(list, false).as_deref(), Some("v2.0.0"));
+        assert_eq!(
+            select(list[..3].iter().copied(), false).as_deref(),
+            Some("v1.10.0")
+        );
+        assert_eq!(
+            select(list[..3].iter().copied(), true).as_deref(),
+            Some("v1.11.0-rc.1")
+        );
+
+        let pseudo = "v0.0.0-20191109021931-daa7c04131f5";
+        assert!(is_pseudo(pseudo));
+        assert!(is_pseudo("v1.2.4-0.20191109021931-daa7c04131f5"));
+        assert!(!is_pseudo("v1.2.3-rc.1"));
+        assert_eq!(select([pseudo], true), None);
+    }
+
+    #[test]
+    fn module_paths() {
+        assert_eq!(
+            escape("github.com/BurntSushi/toml"),
+            "github.com/!burnt!sushi/toml"
+        );
+        assert_eq!(
+            module("https://proxy.golang.org/golang.org/x/tools/"),
+            Some("golang.org/x/tools")
+        );
+        assert_eq!(module("https://proxy.golang.org/"), None);
+    }
+}
@@ -0,0 +1,386 @@
+// backend/listing.rs
+//! Native directory listing parser
+//!
+//! Understands Apache, nginx, and lighttpd autoindex pages, FTP `LIST` output, and plain lists of
+//! filenames, like from `curl --list-only`. Archives named `name-VERSION.tar.*` are extracted
+//! along with their modification times, where the listing has them.
+
+use std::sync::LazyLock;
+use std::time::SystemTime;
+
+use regex::Regex;
+
+/// # An archive found in a directory listing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Archive {
+    pub filename: String,
+    pub version: String,
+    pub mtime: Option<SystemTime>,
+}
+
+/// An entry in a listing, before it's matched against a package name
+#[derive(Debug)]
+struct Entry {
+    filename: String,
+    mtime: Option<SystemTime>,
+}
+
+static ANCHOR: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?is)<a\s[^>]*?href\s*=\s*["']([^"']+)["'][^>]*>"#)
+        .expect("Regex should be valid")
+});
+
+/// A unix-style FTP `LIST` line, like "-rw-r--r-- 1 ftp ftp 1234 Jan 02 10:00 name"
+static FTP_LINE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"^[-dlbcps][-rwxsStT]{9}\S*\s+\d+\s+\S+\s+\S+\s+\d+\s+([A-Za-z]{3})\s+(\d{1,2})\s+(\d{1,2}:\d{2}|\d{4})\s+(.+)$",
+    )
+    .expect("Regex should be valid")
+});
+
+/// The timestamp formats used by autoindex pages:
+/// - Apache: "2024-01-02 10:00"
+/// - nginx and older Apache: "02-Jan-2024 10:00"
+/// - lighttpd: "2024-Jan-02 10:00:00"
+static HTML_TIME: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(concat!(
+        r"(?P<iy>\d{4})-(?P<im>\d{2})-(?P<id>\d{2})\s+(?P<it>\d{2}:\d{2}(?::\d{2})?)",
+        r"|(?P<nd>\d{2})-(?P<nm>[A-Za-z]{3})-(?P<ny>\d{4})\s+(?P<nt>\d{2}:\d{2}(?::\d{2})?)",
+        r"|(?P<ly>\d{4})-(?P<lm>[A-Za-z]{3})-(?P<ld>\d{2})\s+(?P<lt>\d{2}:\d{2}(?::\d{2})?)",
+    ))
+    .expect("Regex should be valid")
+});
+
+/// Archive extensions, like ".tar.xz" or ".tgz"
+static ARCHIVE_EXT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)\.(?:tar(?:\.[a-z0-9]+)?|t[gbx]z|tbz2|tzst)$").expect("Regex should be valid")
+});
+
+static UNDERSCORE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(\d)_(\d)").expect("Regex should be valid"));
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+
+/// # Extract the archives of `name` from a directory listing
+///
+/// Archives are returned in listing order, so a version may appear more than once if it's
+/// offered in several compression formats.
+pub fn archives(listing: &str, name: &str) -> Vec<Archive> {
+    entries(listing)
+        .into_iter()
+        .filter_map(|e| {
+            Some(Archive {
+                version: version(&e.filename, name)?,
+                filename: e.filename,
+                mtime: e.mtime,
+            })
+        })
+        .collect()
+}
+
+/// Extract the version from an archive filename, like "1.2.3" from "name-1.2.3.tar.gz"
+///
+/// Like `vtrim`, the name may be followed by "-" or "_", a "v" prefix is dropped, and
+/// underscores between numbers are treated as dots.
+fn version(filename: &str, name: &str) -> Option<String> {
+    let stem = ARCHIVE_EXT.find(filename).map(|m| &filename[..m.start()])?;
+
+    let rest = stem
+        .get(..name.len())
+        .filter(|prefix| prefix.eq_ignore_ascii_case(name))
+        .map(|_| &stem[name.len()..])?;
+    let rest = rest.strip_prefix(['-', '_']).unwrap_or(rest);
+    let rest = rest.strip_prefix(['v', 'V']).unwrap_or(rest);
+
+    if !rest.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+
+    // replace repeatedly, since matches can't overlap, like in "1_2_3"
+    let mut version = rest.to_string();
+    while UNDERSCORE.is_match(&version) {
+        version = UNDERSCORE.replace_all(&version, "$1.$2").into_owned();
+    }
+    Some(version)
+}
+
+fn entries(listing: &str) -> Vec<Entry> {
+    if ANCHOR.is_match(listing) {
+        return html_entries(listing);
+    }
+
+    listing
+        .lines()
+        .map(str::trim_end)
+        .filter(|l| !l.is_empty())
+        .map(|line| {
+            FTP_LINE.captures(line).map_or_else(
+                || Entry {
+                    filename: line.trim().to_string(),
+                    mtime: None,
+                },
+                |c| Entry {
+                    // symlinks are listed as "name -> target"
+                    filename: c[4].split(" -> ").next().unwrap_or_default().to_string(),
+                    mtime: ftp_time(&c[1], &c[2], &c[3]),
+                },
+            )
+        })
+        .collect()
+}
+
+/// Collect the links of an autoindex page
+///
+/// The modification time is the first timestamp between a link and the next link or line break,
+/// which covers both the table layout of Apache and lighttpd and the preformatted one of nginx.
+fn html_entries(listing: &str) -> Vec<Entry> {
+    let anchors = ANCHOR.captures_iter(listing).collect::<Vec<_>>();
+
+    anchors
+        .iter()
+        .enumerate()
+        .filter_map(|(i, c)| {
+            let filename = filename(&c[1])?;
+
+            let start = c.get(0).map_or(0, |m| m.end());
+            let end = anchors
+                .get(i + 1)
+                .and_then(|next| next.get(0))
+                .map_or(listing.len(), |m| m.start());
+            let after = &listing[start..end];
+            let row = after
+                .find("</tr>")
+                .or_else(|| {
+                    after
+                        .find("</a>")
+                        .and_then(|a| after[a..].find('\n').map(|n| a + n))
+                })
+                .map_or(after, |i| &after[..i]);
+
+            Some(Entry {
+                filename,
+                mtime: HTML_TIME.captures(row).and_then(|t| html_time(&t)),
+            })
+        })
+        .collect()
+}
+
+/// The filename a link points to, ignoring sorting links, directories, and parent links
+fn filename(href: &str) -> Option<String> {
+    let href = href.split(['?', '#']).next().unwrap_or_default();
+    if href.is_empty() || href.ends_with('/') {
+        return None;
+    }
+
+    let last = href.rsplit('/').next().unwrap_or(href);
+    Some(percent_decode(&decode_entities(last)))
+}
+
+fn decode_entities(s: &str) -> String {
+    s.replace("&amp;", "&")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn month(name: &str) -> Option<usize> {
+    MONTHS
+        .iter()
+        .position(|m| m.eq_ignore_ascii_case(name))
+        .map(|i| i + 1)
+}
+
+fn html_time(c: &regex::Captures) -> Option<SystemTime> {
+    let (year, month, day, time) = if let Some(y) = c.name("iy") {
+        (y.as_str(), c["im"].parse().ok()?, &c["id"], &c["it"])
+    } else if let Some(y) = c.name("ny") {
+        (y.as_str(), month(&c["nm"])?, &c["nd"], &c["nt"])
+    } else {
+        (&c["ly"], month(&c["lm"])?, &c["ld"], &c["lt"])
+    };
+
+    timestamp(year, month, day, time)
+}
+
+/// Parse the time of an FTP `LIST` line, which omits the year for files from the last 6 months
+fn ftp_time(month_name: &str, day: &str, time_or_year: &str) -> Option<SystemTime> {
+    let month = month(month_name)?;
+    if !time_or_year.contains(':') {
+        return timestamp(time_or_year, month, day, "00:00");
+    }
+
+    let now = humantime::format_rfc3339_seconds(SystemTime::now()).to_string();
+    let year = now.get(..4)?.parse::<u32>().ok()?;
+    let this_year = timestamp(&year.to_string(), month, day, time_or_year)?;
+
+    // a date in the future must be from last year
+    if this_year > SystemTime::now() {
+        return timestamp(&(year - 1).to_string(), month, day, time_or_year);
+    }
+    Some(this_year)
+}
+
+fn timestamp(year: &str, month: usize, day: &str, time: &str) -> Option<SystemTime> {
+    let seconds = if time.len() == 5 { ":00" } else { "" };
+    let s = format!("{year}-{month:02}-{day:0>2} {time:0>5}{seconds}");
+    humantime::parse_rfc3339_weak(&s).ok()
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn summary(listing: &str, name: &str) -> Vec<(String, String, Option<String>)> {
+        archives(listing, name)
+            .into_iter()
+            .map(|a| {
+                let mtime = a
+                    .mtime
+                    .map(|t| humantime::format_rfc3339_seconds(t).to_string());
+                (a.filename, a.version, mtime)
+            })
+            .collect()
+    }
+
+    fn tuple(filename: &str, version: &str, mtime: &str) -> (String, String, Option<String>) {
+        (filename.into(), version.into(), Some(mtime.into()))
+    }
+
+    #[test]
+    fn apache() {
+        let listing = r#"<table>
+   <tr><th valign="top"><img src="/icons/blank.gif" alt="[ICO]"></th><th><a href="?C=N;O=A">Name</a></th><th><a href="?C=M;O=A">Last modified</a></th></tr>
+   <tr><td valign="top"><img src="/icons/back.gif" alt="[PARENTDIR]"></td><td><a href="/gnu/">Parent Directory</a></td><td>&nbsp;</td></tr>
+<tr><td valign="top"><img src="/icons/compressed.gif" alt="[   ]"></td><td><a href="bash-5.2.37.tar.gz">bash-5.2.37.tar.gz</a></td><td align="right">2024-09-23 17:04  </td><td align="right">10M</td></tr>
+<tr><td valign="top"><img src="/icons/unknown.gif" alt="[   ]"></td><td><a href="bash-5.2.37.tar.gz.sig">bash-5.2.37.tar.gz.sig</a></td><td align="right">2024-09-23 17:04  </td><td align="right">95 </td></tr>
+<tr><td valign="top"><img src="/icons/compressed.gif" alt="[   ]"></td><td><a href="bash-5.3-rc2.tar.gz">bash-5.3-rc2.tar.gz</a></td><td align="right">2025-05-30 09:11  </td><td align="right">11M</td></tr>
+<tr><td valign="top"><img src="/icons/compressed.gif" alt="[   ]"></td><td><a href="bash-doc-5.2.tar.gz">bash-doc-5.2.tar.gz</a></td><td align="right">2022-09-26 15:01  </td><td align="right">2M</td></tr>
+</table>"#;
+
+        assert_eq!(
+            summary(listing, "bash"),
+            [
+                tuple("bash-5.2.37.tar.gz", "5.2.37", "2024-09-23T17:04:00Z"),
+                tuple("bash-5.3-rc2.tar.gz", "5.3-rc2", "2025-05-30T09:11:00Z"),
+            ]
+        );
+    }
+
+    #[test]
+    fn nginx() {
+        let listing = r#"<html>
+<head><title>Index of /pub/ed/</title></head>
+<body>
+<h1>Index of /pub/ed/</h1><hr><pre><a href="../">../</a>
+<a href="old/">old/</a>                                               01-Mar-2019 12:00                   -
+<a href="ed-1.20.2.tar.lz">ed-1.20.2.tar.lz</a>                                   08-Apr-2024 21:12               62456
+<a href="ed-1.21.tar.lz">ed-1.21.tar.lz</a>                                     10-Jan-2025 22:09               63218
+<a href="ed-1.21.tar.lz.sig">ed-1.21.tar.lz.sig</a>                                 10-Jan-2025 22:09                  72
+</pre><hr></body>
+</html>"#;
+
+        assert_eq!(
+            summary(listing, "ed"),
+            [
+                tuple("ed-1.20.2.tar.lz", "1.20.2", "2024-04-08T21:12:00Z"),
+                tuple("ed-1.21.tar.lz", "1.21", "2025-01-10T22:09:00Z"),
+            ]
+        );
+    }
+
+    #[test]
+    fn lighttpd() {
+        let listing = r#"<table summary="Directory Listing" cellpadding="0" cellspacing="0">
+<thead><tr><th class="n">Name</th><th class="m">Last Modified</th><th class="s">Size</th><th class="t">Type</th></tr></thead>
+<tbody>
+<tr class="d"><td class="n"><a href="../">..</a>/</td><td class="m">&nbsp;</td><td class="s">- &nbsp;</td><td class="t">Directory</td></tr>
+<tr><td class="n"><a href="xvidcore-1.3.7.tar.bz2">xvidcore-1.3.7.tar.bz2</a></td><td class="m">2019-Dec-29 00:50:51</td><td class="s">686.6K</td><td class="t">application/x-bzip</td></tr>
+<tr><td class="n"><a href="xvidcore-1.3.7.zip">xvidcore-1.3.7.zip</a></td><td class="m">2019-Dec-29 00:50:53</td><td class="s">1.1M</td><td class="t">application/zip</td></tr>
+</tbody>
+</table>"#;
+
+        assert_eq!(
+            summary(listing, "xvidcore"),
+            [tuple(
+                "xvidcore-1.3.7.tar.bz2",
+                "1.3.7",
+                "2019-12-29T00:50:51Z"
+            )]
+        );
+    }
+
+    #[test]
+    fn ftp() {
+        let listing = "\
+drwxr-xr-x    2 ftp      ftp          4096 Mar 01  2019 old
+-rw-r--r--    1 ftp      ftp       1234567 Jun 10  2023 mpdecimal-2.5.1.tar.gz
+-rw-r--r--    1 ftp      ftp       1234890 Jan 02  2024 mpdecimal-4.0.0.tar.gz
+lrwxrwxrwx    1 ftp      ftp            22 Jan 02  2024 mpdecimal-latest.tar.gz -> mpdecimal-4.0.0.tar.gz
+";
+
+        assert_eq!(
+            summary(listing, "mpdecimal"),
+            [
+                tuple("mpdecimal-2.5.1.tar.gz", "2.5.1", "2023-06-10T00:00:00Z"),
+                tuple("mpdecimal-4.0.0.tar.gz", "4.0.0", "2024-01-02T00:00:00Z"),
+            ]
+        );
+
+        // recent files have a time instead of a year
+        let recent = "-rw-r--r-- 1 ftp ftp 1 Jan 02 10:00 mpdecimal-4.0.1.tar.gz";
+        let archive = &archives(recent, "mpdecimal")[0];
+        assert_eq!(archive.version, "4.0.1");
+        assert!(archive.mtime.unwrap() <= SystemTime::now());
+    }
+
+    #[test]
+    fn plain_and_names() {
+        let listing =
+            "groff-1.23.0.tar.gz\ngroff_1_22_4.tar.gz\nGroff-v1.24.0.tgz\ngroffer-1.0.tar.gz\n";
+        assert_eq!(
+            archives(listing, "groff")
+                .into_iter()
+                .map(|a| a.version)
+                .collect::<Vec<_>>(),
+            ["1.23.0", "1.22.4", "1.24.0"]
+        );
+
+        assert_eq!(
+            filename("https://example.com/dl/foo%2Bbar-1.0.tar.gz?raw=1"),
+            Some("foo+bar-1.0.tar.gz".into())
+        );
+        assert_eq!(filename("?C=M;O=A"), None);
+        assert_eq!(filename("../"), None);
+    }
+}
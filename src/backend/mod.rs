@@ -0,0 +1,8 @@
+// backend/mod.rs
+
+pub mod git;
+pub mod go;
+pub mod forge;
+pub mod listing;
+pub mod npm;
+pub mod pypi;
@@ -0,0 +1,116 @@
+// backend/npm.rs
+//! Native npm registry backend using dist-tags
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::CONFIG;
+use crate::utils::http::{self, HttpError};
+use crate::utils::ver;
+
+/// The public registry, which upstreams may point to even when a mirror is configured
+const PUBLIC: &str = "https://registry.npmjs.org";
+
+#[derive(Error, Debug)]
+pub enum NpmError {
+    #[error("not an npm package url: '{0}'")]
+    InvalidPackage(String),
+
+    #[error(transparent)]
+    Http(#[from] HttpError),
+
+    #[error("unexpected response")]
+    Json(#[from] serde_json::Error),
+
+    #[error("no 'latest' dist-tag")]
+    NoLatest,
+}
+
+#[derive(Debug, Deserialize)]
+struct Packument {
+    #[serde(rename = "dist-tags")]
+    dist_tags: HashMap<String, String>,
+}
+
+/// The configured registry, without a trailing slash
+fn registry() -> &'static str {
+    let config = CONFIG.get().expect("Config should be initialized");
+    config.registries.npm.trim_end_matches('/')
+}
+
+/// Extract the package name from an upstream on the public or configured registry
+pub fn package(upstream: &str) -> Option<&str> {
+    [PUBLIC, registry()]
+        .into_iter()
+        .find_map(|base| upstream.strip_prefix(base)?.strip_prefix('/'))
+        .map(|name| name.trim_end_matches('/'))
+        .filter(|name| !name.is_empty())
+}
+
+/// # Fetch the latest version of an npm package
+///
+/// This is the `latest` dist-tag, or with `unstable`, whichever is newer of it and `next`.
+pub fn latest(upstream: &str, unstable: bool) -> Result<String, NpmError> {
+    let name = package(upstream).ok_or_else(|| NpmError::InvalidPackage(upstream.to_string()))?;
+    latest_from(registry(), name, unstable)
+}
+
+fn latest_from(registry: &str, name: &str, unstable: bool) -> Result<String, NpmError> {
+    // scoped packages are requested as "@scope%2fname"
+    let url = format!("{registry}/{}", name.replace('/', "%2f"));
+    // the abbreviated packument differs from what `cr` would get, so it has its own kind
+    let packument = http::get_cached("npm", &url, &[("Accept", "application/vnd.npm.install-v1+json")])?;
+    select(serde_json::from_slice(&packument)?, unstable)
+}
+
+fn select(packument: Packument, unstable: bool) -> Result<String, NpmError> {
+    let mut tags = packument.dist_tags;
+    let latest = tags.remove("latest").ok_or(NpmError::NoLatest)?;
+
+    Ok(match tags.remove("next") {
+        Some(next) if unstable && ver::compare(&next, &latest) == Ordering::Greater => next,
+        _ => latest,
+    })
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn packument(json: &str) -> Packument {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn dist_tags() {
+        let p = || packument(r#"{"dist-tags": {"latest": "5.4.0", "next": "5.5.0-beta.2"}}"#);
+        assert_eq!(select(p(), false).unwrap(), "5.4.0");
+        assert_eq!(select(p(), true).unwrap(), "5.5.0-beta.2");
+
+        // a stale next tag is ignored
+        let p = packument(r#"{"dist-tags": {"latest": "5.4.0", "next": "5.0.0-rc.1"}}"#);
+        assert_eq!(select(p, true).unwrap(), "5.4.0");
+
+        let p = packument(r#"{"dist-tags": {"beta": "1.0.0-beta.1"}}"#);
+        assert!(matches!(select(p, false), Err(NpmError::NoLatest)));
+    }
+
+    #[test]
+    fn package_names() {
+        assert_eq!(
+            package("https://registry.npmjs.org/typescript"),
+            Some("typescript")
+        );
+        assert_eq!(
+            package("https://registry.npmjs.org/@types/node/"),
+            Some("@types/node")
+        );
+        assert_eq!(package("https://registry.npmjs.org/"), None);
+        assert_eq!(package("https://github.com/microsoft/TypeScript"), None);
+    }
+}
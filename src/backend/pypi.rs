@@ -0,0 +1,290 @@
+// backend/pypi.rs
+//! Native `PyPI` backend using the JSON API
+//!
+//! Versions are parsed and ordered according to PEP 440. Versions that don't parse, like some
+//! legacy releases, are ignored.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::utils::http::{self, HttpError};
+
+#[derive(Error, Debug)]
+pub enum PyPIError {
+    #[error("not a pypi project url: '{0}'")]
+    InvalidProject(String),
+
+    #[error(transparent)]
+    Http(#[from] HttpError),
+
+    #[error("unexpected response")]
+    Json(#[from] serde_json::Error),
+
+    #[error("no matching versions")]
+    NoVersions,
+}
+
+/// # A version parsed according to PEP 440
+#[derive(Debug, Clone)]
+pub struct Pep440 {
+    epoch: u64,
+    release: Vec<u64>,
+    pre: Option<(PreKind, u64)>,
+    post: Option<u64>,
+    dev: Option<u64>,
+    local: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum PreKind {
+    Alpha,
+    Beta,
+    Rc,
+}
+
+impl Pep440 {
+    /// Parse a version, accepting the alternative spellings PEP 440 normalizes
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim().to_lowercase();
+        let s = s.strip_prefix('v').unwrap_or(&s);
+
+        let (s, local) = match s.split_once('+') {
+            Some((s, local)) if !local.is_empty() => (s, Some(local.to_string())),
+            Some(_) => return None,
+            None => (s, None),
+        };
+
+        let (epoch, s) = match s.split_once('!') {
+            Some((epoch, s)) => (epoch.parse().ok()?, s),
+            None => (0, s),
+        };
+
+        let release_end = s
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(s.len());
+        let release_str = s[..release_end].trim_end_matches('.');
+        let release = release_str
+            .split('.')
+            .map(|n| n.parse().ok())
+            .collect::<Option<Vec<u64>>>()?;
+
+        let mut version = Self {
+            epoch,
+            release,
+            pre: None,
+            post: None,
+            dev: None,
+            local,
+        };
+
+        let mut rest = &s[release_str.len()..];
+        while !rest.is_empty() {
+            rest = rest.trim_start_matches(['.', '-', '_']);
+            let word_end = rest
+                .find(|c: char| !c.is_ascii_alphabetic())
+                .unwrap_or(rest.len());
+            let (word, after) = rest.split_at(word_end);
+            let after = after.trim_start_matches(['.', '-', '_']);
+            let num_end = after
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(after.len());
+            let num = after[..num_end].parse().unwrap_or(0);
+
+            match word {
+                "a" | "alpha" if version.pre.is_none() => version.pre = Some((PreKind::Alpha, num)),
+                "b" | "beta" if version.pre.is_none() => version.pre = Some((PreKind::Beta, num)),
+                "rc" | "c" | "pre" | "preview" if version.pre.is_none() => {
+                    version.pre = Some((PreKind::Rc, num));
+                }
+                "post" | "rev" | "r" if version.post.is_none() => version.post = Some(num),
+                "dev" if version.dev.is_none() => version.dev = Some(num),
+                // an implicit post release, like "1.0-1"
+                "" if num_end > 0 && version.post.is_none() => version.post = Some(num),
+                _ => return None,
+            }
+
+            rest = &after[num_end..];
+        }
+
+        Some(version)
+    }
+
+    /// Whether this is a final release, without pre, post, dev, or local parts
+    pub const fn is_final(&self) -> bool {
+        self.pre.is_none() && self.post.is_none() && self.dev.is_none() && self.local.is_none()
+    }
+
+    /// Whether this is an alpha, beta, or release candidate, without dev or local parts
+    pub const fn is_prerelease(&self) -> bool {
+        self.pre.is_some() && self.dev.is_none() && self.local.is_none()
+    }
+
+    /// The release segment without trailing zeros, which are insignificant
+    fn trimmed_release(&self) -> &[u64] {
+        let len = self
+            .release
+            .iter()
+            .rposition(|&n| n != 0)
+            .map_or(0, |i| i + 1);
+        &self.release[..len]
+    }
+}
+
+impl Ord for Pep440 {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // a dev release of a final release sorts before its pre-releases
+        let pre = |v: &Self| match (v.pre, v.post, v.dev) {
+            (None, None, Some(_)) => (0, None),
+            (Some(pre), ..) => (1, Some(pre)),
+            _ => (2, None),
+        };
+        let dev = |v: &Self| v.dev.map_or((1, 0), |d| (0, d));
+
+        self.epoch
+            .cmp(&other.epoch)
+            .then_with(|| self.trimmed_release().cmp(other.trimmed_release()))
+            .then_with(|| pre(self).cmp(&pre(other)))
+            .then_with(|| self.post.cmp(&other.post))
+            .then_with(|| dev(self).cmp(&dev(other)))
+            .then_with(|| self.local.cmp(&other.local))
+    }
+}
+
+impl PartialEq for Pep440 {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for Pep440 {}
+
+impl PartialOrd for Pep440 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Project {
+    releases: HashMap<String, Vec<File>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct File {
+    #[serde(default)]
+    yanked: bool,
+}
+
+/// # Fetch the latest version of a `PyPI` project
+///
+/// With `unstable`, pre-releases are considered too, so the result is whichever is newer of the
+/// latest release and the latest pre-release.
+pub fn latest(upstream: &str, unstable: bool) -> Result<String, PyPIError> {
+    let url = json_url(upstream)?;
+    let project = http::get_cached("pypi", &url, &[("Accept", "application/json")])?;
+    let project = serde_json::from_slice::<Project>(&project)?;
+
+    select(&project, unstable).ok_or(PyPIError::NoVersions)
+}
+
+/// Map `https://pypi.org/project/name` to `https://pypi.org/pypi/name/json`
+fn json_url(upstream: &str) -> Result<String, PyPIError> {
+    let (origin, name) = upstream
+        .trim_end_matches('/')
+        .rsplit_once("/project/")
+        .filter(|(_, name)| !name.is_empty() && !name.contains('/'))
+        .ok_or_else(|| PyPIError::InvalidProject(upstream.to_string()))?;
+
+    Ok(format!("{origin}/pypi/{name}/json"))
+}
+
+fn select(project: &Project, unstable: bool) -> Option<String> {
+    project
+        .releases
+        .iter()
+        // releases without files haven't been uploaded, and fully yanked ones shouldn't be used
+        .filter(|(_, files)| files.iter().any(|f| !f.yanked))
+        .filter_map(|(raw, _)| Some((Pep440::parse(raw)?, raw)))
+        .filter(|(v, _)| v.is_final() || (unstable && v.is_prerelease()))
+        // ties like "1.0" and "1.0.0" are broken by the raw string, since map order is random
+        .max_by(|(a, ra), (b, rb)| a.cmp(b).then_with(|| ra.cmp(rb)))
+        .map(|(_, raw)| raw.clone())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn sorted(versions: &[&str]) -> Vec<String> {
+        let mut v = versions
+            .iter()
+            .map(|v| (Pep440::parse(v).unwrap(), v.to_string()))
+            .collect::<Vec<_>>();
+        v.sort_by(|a, b| a.0.cmp(&b.0));
+        v.into_iter().map(|(_, raw)| raw).collect()
+    }
+
+    #[test]
+    fn pep440_ordering() {
+        assert_eq!(
+            sorted(&[
+                "1.0.post1",
+                "1.0",
+                "1.0rc1",
+                "1.0.dev0",
+                "1.0a1",
+                "1.0b2.post1",
+                "1.0b2",
+                "1.0a1.dev1",
+                "1!0.1",
+                "0.9",
+                "1.1.dev1",
+            ]),
+            [
+                "0.9",
+                "1.0.dev0",
+                "1.0a1.dev1",
+                "1.0a1",
+                "1.0b2",
+                "1.0b2.post1",
+                "1.0rc1",
+                "1.0",
+                "1.0.post1",
+                "1.1.dev1",
+                "1!0.1",
+            ]
+        );
+        assert_eq!(Pep440::parse("1.0"), Pep440::parse("1.0.0"));
+        assert_eq!(Pep440::parse("1.0-alpha.1"), Pep440::parse("1.0a1"));
+        assert!(Pep440::parse("1.0-foo").is_none());
+    }
+
+    #[test]
+    fn select_versions() {
+        let project = serde_json::from_str::<Project>(
+            r#"{"releases": {
+                "1.0": [{"yanked": false}],
+                "1.1": [{"yanked": true}],
+                "1.2rc1": [{"yanked": false}],
+                "1.2.dev1": [{"yanked": false}],
+                "1.3": [],
+                "not-a-version": [{"yanked": false}]
+            }}"#,
+        )
+        .unwrap();
+
+        assert_eq!(select(&project, false).as_deref(), Some("1.0"));
+        assert_eq!(select(&project, true).as_deref(), Some("1.2rc1"));
+
+        assert_eq!(
+            json_url("https://pypi.org/project/build/").unwrap(),
+            "https://pypi.org/pypi/build/json"
+        );
+        assert!(json_url("https://pypi.org/").is_err());
+    }
+}
@@ -1,19 +1,226 @@
+use std::collections::HashMap;
 use std::fs;
+use std::path::PathBuf;
 
 use color_eyre::eyre::Context;
 use color_eyre::Result;
 use serde::Deserialize;
 use crate::VAT_ROOT;
+use crate::backend::forge::Forge;
 
 #[derive(Debug, Deserialize)]
 pub struct Shortform {
     pub short: String,
     pub full: String,
+
+    /// The release API spoken by the forge the shortform points to
+    #[serde(default)]
+    pub forge: Option<Forge>,
 }
 
 impl Shortform {
-    fn new<S1: Into<String>, S2: Into<String>>(short: S1, full: S2) -> Self {
-        Self { short: short.into(), full: full.into() }
+    fn new<S1: Into<String>, S2: Into<String>>(short: S1, full: S2, forge: Option<Forge>) -> Self {
+        Self { short: short.into(), full: full.into(), forge }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StderrPattern {
+    /// A regex matched against stderr, with a `code` capture group for http errors
+    pub pattern: String,
+
+    /// The kind of error, e.g. "http", "dns", "tls", or "rate-limited"
+    pub error: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Schedule {
+    /// Channels that changed within this many days are always checked. Older channels are
+    /// checked with a chance of `scale / age`.
+    pub scale: u64,
+
+    /// The lowest chance a channel will be checked with
+    pub min_chance: f64,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Self {
+            scale: 120,
+            min_chance: 0.01,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct GitHub {
+    /// The base URL of the REST API
+    pub api: String,
+
+    /// A token for the REST API. `GH_TOKEN` in the environment takes precedence.
+    pub token: Option<String>,
+}
+
+impl Default for GitHub {
+    fn default() -> Self {
+        Self {
+            api: "https://api.github.com".into(),
+            token: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Registries {
+    /// The base URL of the npm registry
+    pub npm: String,
+
+    /// The base URL of the Go module proxy
+    pub go: String,
+}
+
+impl Default for Registries {
+    fn default() -> Self {
+        Self {
+            npm: "https://registry.npmjs.org".into(),
+            go: "https://proxy.golang.org".into(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Throttle {
+    /// Maximum concurrent fetches per host, or unlimited if 0
+    pub concurrency: usize,
+
+    /// Maximum fetches per second per host, or unlimited if 0
+    pub rate: f64,
+
+    /// Fetches a host may receive in quick succession before `rate` applies
+    pub burst: f64,
+
+    /// Limits for specific hosts, which also apply to their subdomains
+    pub hosts: Vec<HostThrottle>,
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Self {
+            concurrency: 4,
+            rate: 0.0,
+            burst: 4.0,
+            hosts: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HostThrottle {
+    pub host: String,
+    pub concurrency: Option<usize>,
+    pub rate: Option<f64>,
+    pub burst: Option<f64>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(default)]
+pub struct Retry {
+    /// Attempts per fetch, including the first, where only transient failures are retried
+    pub attempts: u32,
+
+    /// Longest wait before the first retry (in seconds), which doubles with each retry
+    pub backoff: f64,
+
+    /// Longest wait between attempts (in seconds)
+    pub max_backoff: f64,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            backoff: 2.0,
+            max_backoff: 30.0,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Breaker {
+    /// Consecutive connection failures before a host is tripped, or never if 0
+    pub threshold: usize,
+
+    /// Time between probes of a tripped host (in seconds)
+    pub interval: u64,
+}
+
+impl Default for Breaker {
+    fn default() -> Self {
+        Self {
+            threshold: 3,
+            interval: 60,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Cache {
+    /// Largest total size of cached responses (in bytes), or unbounded if 0
+    pub max_size: u64,
+
+    /// Lifespans of entries by kind (in seconds), overriding `cache_timeout`
+    pub ttl: HashMap<String, u64>,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Self {
+            max_size: 256 * 1024 * 1024,
+            ttl: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Sandbox {
+    /// Whether fetch commands are sandboxed at all
+    pub enabled: bool,
+
+    /// Isolate PIDs and IPC in new namespaces
+    pub namespaces: bool,
+
+    /// Make the filesystem read-only with Landlock, except the cache and `writable`
+    pub landlock: bool,
+
+    /// Environment variables passed through to fetch commands
+    pub env: Vec<String>,
+
+    /// Extra paths fetch commands may write to
+    pub writable: Vec<PathBuf>,
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            namespaces: true,
+            landlock: true,
+            env: [
+                "PATH", "HOME", "LANG", "LC_ALL", "TERM", "TZ", "http_proxy", "https_proxy",
+                "no_proxy", "HTTP_PROXY", "HTTPS_PROXY", "NO_PROXY", "SSL_CERT_FILE",
+                "SSL_CERT_DIR",
+            ]
+            .map(String::from)
+            .to_vec(),
+            writable: vec![],
+        }
     }
 }
 
@@ -22,11 +229,47 @@ pub struct Config {
     /// Maximum lifespan for a fetch (in seconds)
     pub fetch_timeout: u64,
 
-    /// Maximum lifespan for .vat-cache (in seconds)
+    /// Default lifespan for entries in .vat-cache (in seconds)
     pub cache_timeout: u64,
 
+    /// Limits for .vat-cache
+    #[serde(default)]
+    pub cache: Cache,
+
     /// Shortforms for upstream URLs
     pub shortforms: Vec<Shortform>,
+
+    /// Extra patterns for classifying errors in stderr
+    #[serde(default)]
+    pub stderr_patterns: Vec<StderrPattern>,
+
+    /// Adaptive scheduling for channels without an explicit chance
+    #[serde(default)]
+    pub schedule: Schedule,
+
+    /// The GitHub API
+    #[serde(default)]
+    pub github: GitHub,
+
+    /// Package registries and proxies
+    #[serde(default)]
+    pub registries: Registries,
+
+    /// Confinement for fetch commands
+    #[serde(default)]
+    pub sandbox: Sandbox,
+
+    /// Per-host limits for bulk fetching
+    #[serde(default)]
+    pub throttle: Throttle,
+
+    /// Circuit breaking for hosts that are down
+    #[serde(default)]
+    pub breaker: Breaker,
+
+    /// Retrying transient fetch failures
+    #[serde(default)]
+    pub retry: Retry,
 }
 
 impl Default for Config {
@@ -34,7 +277,16 @@ impl Default for Config {
         Self {
             fetch_timeout: 30,
             cache_timeout: 3600,
+            cache: Cache::default(),
             shortforms: default_shortforms(),
+            stderr_patterns: vec![],
+            schedule: Schedule::default(),
+            github: GitHub::default(),
+            registries: Registries::default(),
+            sandbox: Sandbox::default(),
+            throttle: Throttle::default(),
+            breaker: Breaker::default(),
+            retry: Retry::default(),
         }
     }
 }
@@ -56,34 +308,43 @@ impl Config {
 fn default_shortforms() -> Vec<Shortform> {
     vec![
         // GitHub aliases
-        Shortform::new("github:", "https://github.com/"),
-        Shortform::new("gh:", "https://github.com/"),
+        Shortform::new("github:", "https://github.com/", Some(Forge::GitHub)),
+        Shortform::new("gh:", "https://github.com/", Some(Forge::GitHub)),
 
         // GitLab aliases
-        Shortform::new("gitlab:", "https://gitlab.com/"),
-        Shortform::new("gl:", "https://gitlab.com/"),
+        Shortform::new("gitlab:", "https://gitlab.com/", Some(Forge::GitLab)),
+        Shortform::new("gl:", "https://gitlab.com/", Some(Forge::GitLab)),
 
         // Dotgay aliases
-        Shortform::new("dotgay:", "https://git.gay/"),
+        Shortform::new("dotgay:", "https://git.gay/", Some(Forge::Gitea)),
 
         // Codeberg aliases
-        Shortform::new("codeberg:", "https://codeberg.org/"),
-        Shortform::new("cb:", "https://codeberg.org/"),
+        Shortform::new("codeberg:", "https://codeberg.org/", Some(Forge::Gitea)),
+        Shortform::new("cb:", "https://codeberg.org/", Some(Forge::Gitea)),
 
         // Freedesktop aliases
-        Shortform::new("freedesktop:", "https://gitlab.freedesktop.org/"),
+        Shortform::new("freedesktop:", "https://gitlab.freedesktop.org/", Some(Forge::GitLab)),
 
         // Inria aliases
-        Shortform::new("inria:", "https://gitlab.inria.fr/"),
+        Shortform::new("inria:", "https://gitlab.inria.fr/", Some(Forge::GitLab)),
 
         // Salsa aliases
-        Shortform::new("salsa:", "https://salsa.debian.org/"),
+        Shortform::new("salsa:", "https://salsa.debian.org/", Some(Forge::GitLab)),
+
+        // PyPI aliases
+        Shortform::new("pypi:", "https://pypi.org/project/", None),
+
+        // npm aliases
+        Shortform::new("npm:", "https://registry.npmjs.org/", None),
+
+        // Go aliases
+        Shortform::new("go:", "https://proxy.golang.org/", None),
 
         // Kernel aliases
-        Shortform::new("kernel:", "https://git.kernel.org/pub/scm/"),
+        Shortform::new("kernel:", "https://git.kernel.org/pub/scm/", None),
 
         // SourceHut aliases
-        Shortform::new("sourcehut:", "https://git.sr.ht/~"),
-        Shortform::new("srht:", "https://git.sr.ht/~"),
+        Shortform::new("sourcehut:", "https://git.sr.ht/~", None),
+        Shortform::new("srht:", "https://git.sr.ht/~", None),
     ]
 }
@@ -1,18 +1,25 @@
 use color_eyre::config::HookBuilder;
-use color_eyre::eyre::WrapErr;
-use std::os::unix::fs::MetadataExt;
+use color_eyre::eyre::{WrapErr, bail, eyre};
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
 use std::sync::{LazyLock, OnceLock};
-use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::time::Instant;
 use std::{env, fs};
 
-use self::args::ARGS;
+use self::args::{ARGS, CacheCommand, Command, ForgeQuery, Registry, VersionQuery};
+use self::backend::git;
+use self::backend::{forge, go, listing, npm, pypi};
 use self::config::Config;
-use self::package::{Package, bulk};
+use self::package::{Package, bulk, explain, stale, test_config};
+use self::utils::cache::{self, CACHE};
+use self::utils::fixtures::{self, FIXTURES};
+use self::utils::http;
 use self::utils::log::log;
+use self::utils::ver;
 use color_eyre::Result;
 
 mod args;
+mod backend;
 mod config;
 mod package;
 mod utils;
@@ -20,14 +27,26 @@ mod utils;
 #[macro_use]
 extern crate tracing;
 
-static VAT_ROOT: LazyLock<PathBuf> =
-    LazyLock::new(|| env::current_dir().expect("Couldn't get working directory"));
+// fetch commands run from the package directory, so subcommands invoked by them inherit the root
+static VAT_ROOT: LazyLock<PathBuf> = LazyLock::new(|| {
+    env::var_os("VAT_ROOT")
+        .map_or_else(|| env::current_dir().expect("Couldn't get working directory"), PathBuf::from)
+});
+
+static VAT_BIN: LazyLock<PathBuf> =
+    LazyLock::new(|| env::current_exe().expect("Couldn't get the path to vat"));
 
 static VAT_CACHE: LazyLock<PathBuf> = LazyLock::new(|| VAT_ROOT.join(".vat-cache"));
 
+// unlike the cache, state like stored etags is kept between runs
+static VAT_STATE: LazyLock<PathBuf> = LazyLock::new(|| VAT_ROOT.join(".vat-state"));
+
 static SHLIB_PATH: LazyLock<PathBuf> = LazyLock::new(|| VAT_ROOT.join("sh/lib.env"));
 
-static NO_CACHE: LazyLock<bool> = LazyLock::new(|| ARGS.no_cache);
+// cached responses would neither be recorded nor come from fixtures
+static NO_CACHE: LazyLock<bool> = LazyLock::new(|| ARGS.no_cache || FIXTURES.get().is_some());
+
+static NO_SANDBOX: LazyLock<bool> = LazyLock::new(|| ARGS.no_sandbox);
 
 static CONFIG: OnceLock<Config> = OnceLock::new();
 
@@ -50,20 +69,24 @@ fn main() -> color_eyre::Result<()> {
     log();
     CONFIG.set(Config::parse()?)
         .expect("Only one thread should be setting the config");
+    fixtures::init(ARGS.record.as_deref(), ARGS.replay.as_deref())?;
+
+    // subcommands are also called from fetch commands, so keep errors to a single line on stderr
+    if let Some(command) = &ARGS.command {
+        if let Err(e) = run_command(command) {
+            eprintln!("vat: {e:#}");
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
 
-    clean_cache()?;
+    CACHE.clean().wrap_err("Failed to clean cache")?;
+    forge::prune_store();
     let start_timestamp = Instant::now();
 
     debug!("Determined Vat root to be {}", VAT_ROOT.display());
 
-    let packages = if ARGS.packages.is_empty() {
-        bulk::find_all()?
-    } else {
-        ARGS.packages
-            .iter()
-            .map(|s| Package::from_name(s.clone()))
-            .collect::<Result<Vec<_>>>()?
-    };
+    let packages = find_packages(&ARGS.packages)?;
 
     debug!("Detected packages: {packages:#?}");
     let map = bulk::fetch_all(&packages)?;
@@ -81,6 +104,192 @@ fn main() -> color_eyre::Result<()> {
     Ok(())
 }
 
+/// Find packages by name, or all packages if none are named
+fn find_packages(names: &[String]) -> Result<Vec<Package>> {
+    if names.is_empty() {
+        return bulk::find_all();
+    }
+
+    names
+        .iter()
+        .map(|s| Package::from_name(s.clone()))
+        .collect::<Result<Vec<_>>>()
+}
+
+fn run_command(command: &Command) -> Result<()> {
+    let out = match command {
+        Command::LsRemote { url } => git::ls_refs(url, &["HEAD", "refs/tags/"])?.to_ls_remote(),
+        Command::Cmp { a, b } => match ver::compare(a, b) {
+            std::cmp::Ordering::Less => "<\n".into(),
+            std::cmp::Ordering::Equal => "=\n".into(),
+            std::cmp::Ordering::Greater => ">\n".into(),
+        },
+        Command::Stale { packages, days, json } => {
+            stale::report(&find_packages(packages)?, *days, *json)?
+        }
+        Command::TestConfig { packages } => {
+            let (report, failed) = test_config::report(&find_packages(packages)?)?;
+            if failed > 0 {
+                // the report says what failed, so it's printed even though this is an error
+                let _ = io::stdout().write_all(report.as_bytes());
+                bail!("{failed} tests failed");
+            }
+            report
+        }
+        Command::Explain {
+            package,
+            channel,
+            all,
+        } => explain::report(&Package::from_name(package)?, channel.as_deref(), *all)?,
+        Command::Forge { query, upstream } => match query {
+            ForgeQuery::Release => forge::latest_release(upstream)? + "\n",
+            ForgeQuery::Unstable => forge::latest_unstable(upstream)? + "\n",
+            ForgeQuery::Commit => forge::latest_commit(upstream)? + "\n",
+        },
+        Command::Registry {
+            registry,
+            query,
+            upstream,
+        } => {
+            let unstable = matches!(query, VersionQuery::Unstable);
+            let version = match registry {
+                Registry::Pypi => pypi::latest(upstream, unstable)?,
+                Registry::Npm => npm::latest(upstream, unstable)?,
+                Registry::Go => go::latest(upstream, unstable)?,
+            };
+            version + "\n"
+        }
+        Command::Listing { name, long } => {
+            let mut input = String::new();
+            io::stdin().read_to_string(&mut input)?;
+
+            let archives = listing::archives(&input, name);
+            if *long {
+                archives.iter().fold(String::new(), |s, a| {
+                    let mtime = a
+                        .mtime
+                        .map(|t| humantime::format_rfc3339_seconds(t).to_string())
+                        .unwrap_or_default();
+                    s + &a.filename + "\t" + &a.version + "\t" + &mtime + "\n"
+                })
+            } else {
+                archives.iter().fold(String::new(), |s, a| s + &a.version + "\n")
+            }
+        }
+        Command::Sort { reverse } => {
+            let mut input = String::new();
+            io::stdin().read_to_string(&mut input)?;
+
+            let mut versions = input.lines().filter(|l| !l.trim().is_empty()).collect::<Vec<_>>();
+            ver::sort(&mut versions);
+            if *reverse {
+                versions.reverse();
+            }
+
+            versions.iter().fold(String::new(), |s, v| s + v + "\n")
+        }
+        Command::Http {
+            url,
+            output,
+            dump_header,
+        } => {
+            let res = http::get(url, &[])?;
+            if let Some(path) = dump_header {
+                fs::write(path, res.dump_headers())?;
+            }
+
+            let res = res.error_for_status()?;
+            if let Some(path) = output {
+                fs::write(path, &res.body)?;
+                String::new()
+            } else {
+                String::from_utf8_lossy(&res.body).into_owned()
+            }
+        }
+        Command::Cache { command } => cache_command(command)?,
+    };
+
+    // a closed pipe, like from `head`, isn't an error
+    match io::stdout().write_all(out.as_bytes()) {
+        Err(e) if e.kind() != io::ErrorKind::BrokenPipe => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn cache_command(command: &CacheCommand) -> Result<String> {
+    Ok(match command {
+        CacheCommand::Ls { json } => CACHE.ls(*json)?,
+        CacheCommand::Stat => CACHE.stat(),
+        CacheCommand::Purge {
+            expired,
+            kind,
+            sources,
+        } => {
+            let (n, size) = CACHE.purge(&cache::Filter {
+                expired: *expired,
+                kind: kind.as_deref(),
+                sources,
+            });
+            format!("Removed {n} entries ({})\n", cache::human_size(size))
+        }
+        CacheCommand::Get { kind, source } => {
+            // a miss is expected, so it's reported by the exit code alone
+            let Some(path) = CACHE.get(kind, source) else {
+                std::process::exit(1);
+            };
+            format!("{}\n", path.display())
+        }
+        CacheCommand::Fetch {
+            kind,
+            source,
+            refresh,
+            headers,
+            command,
+        } => {
+            let path = CACHE.fetch(kind, source, *refresh, || {
+                let (program, args) =
+                    command.split_first().ok_or_else(|| eyre!("Missing command"))?;
+                let output = std::process::Command::new(program)
+                    .args(args)
+                    .stderr(std::process::Stdio::inherit())
+                    .output()
+                    .wrap_err_with(|| format!("Failed to run '{program}'"))?;
+
+                // the command explains its own failure, and the lock is released on exit
+                if !output.status.success() {
+                    std::process::exit(output.status.code().unwrap_or(1));
+                }
+
+                let headers = headers
+                    .as_ref()
+                    .map(fs::read_to_string)
+                    .transpose()
+                    .wrap_err("Failed to read headers")?;
+                Ok((output.stdout, headers))
+            })?;
+            format!("{}\n", path.display())
+        }
+        CacheCommand::Put {
+            kind,
+            source,
+            headers,
+        } => {
+            let mut data = vec![];
+            io::stdin().read_to_end(&mut data)?;
+            let headers = headers
+                .as_ref()
+                .map(fs::read_to_string)
+                .transpose()
+                .wrap_err("Failed to read headers")?;
+
+            let path = CACHE
+                .put(kind, source, &data, headers.as_deref())
+                .wrap_err("Failed to write to cache")?;
+            format!("{}\n", path.display())
+        }
+    })
+}
+
 fn increment_runcount() -> Result<()> {
     let path = Path::new("runcount");
     let runcount = fs::read_to_string(path)
@@ -91,28 +300,3 @@ fn increment_runcount() -> Result<()> {
     fs::write(path, runcount.to_string())?;
     Ok(())
 }
-
-fn clean_cache() -> Result<()> {
-    let cache_path = &*VAT_CACHE;
-    if let Ok(m) = cache_path.metadata() {
-        #[allow(clippy::cast_sign_loss)]
-        let mtime = Duration::from_secs(m.mtime() as u64);
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .wrap_err("Time travel detected")?;
-
-        let cache_timeout = Duration::from_secs(
-            CONFIG.get().expect("Config should be initialized").cache_timeout
-        );
-
-        if now - mtime > cache_timeout {
-            debug!("Removing cache");
-            fs::remove_dir_all(cache_path).wrap_err("Failed to remove cache")?;
-            fs::create_dir(cache_path).wrap_err("Failed to create cache")?;
-        }
-    } else {
-        fs::create_dir(cache_path).wrap_err("Failed to create cache")?;
-    }
-
-    Ok(())
-}
@@ -3,15 +3,25 @@
 use crate::VAT_CACHE;
 use crate::package::PackageVersions;
 
+use super::outcome::{FetchError, FetchOutcome};
+use super::schedule;
 use super::{Package, VersionChannel};
+use crate::utils::breaker::BREAKER;
+use crate::utils::throttle::{self, Busy, THROTTLE};
 use color_eyre::Result;
-use color_eyre::eyre::{Context, Error};
+use color_eyre::eyre::Context;
 use indexmap::IndexMap;
-use rayon::prelude::*;
+use std::collections::VecDeque;
+use std::fmt::Write;
 use std::path::Path;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
 use std::{env, fs};
 use walkdir::WalkDir;
 
+/// The longest a worker waits before looking for a ready package again
+const MAX_WAIT: Duration = Duration::from_millis(250);
+
 pub fn find_all() -> Result<Vec<Package>> {
     let mut packages = Vec::with_capacity(512);
 
@@ -32,6 +42,227 @@ pub fn find_all() -> Result<Vec<Package>> {
     Ok(packages)
 }
 
+/// # Statistics for a bulk fetch
+#[derive(Debug, Default)]
+pub struct RunStats {
+    pub total: usize,
+    pub skipped: usize,
+    /// Packages where no channel was fetched successfully
+    pub failed: usize,
+    /// Failed channels with their package and why they failed
+    pub failures: Vec<(String, String, FetchError)>,
+
+    pub channels_total: usize,
+    pub channels_skipped: usize,
+    /// Fetched channels with their package and the attempts they took
+    pub attempts: Vec<(String, String, u32)>,
+
+    /// Hosts the circuit breaker tripped on
+    pub tripped: Vec<String>,
+}
+
+impl RunStats {
+    pub const fn checked(&self) -> usize {
+        self.total - self.skipped - self.failed
+    }
+
+    pub const fn channels_checked(&self) -> usize {
+        self.channels_total - self.channels_skipped - self.failures.len()
+    }
+
+    pub fn record(&mut self, package: &Package, outcome: FetchOutcome) {
+        let enabled = package.config.channels.iter().filter(|c| c.enabled).count();
+        self.total += 1;
+        self.channels_total += enabled;
+
+        let failed = match outcome {
+            FetchOutcome::Fetched {
+                skipped,
+                failed,
+                attempts,
+                ..
+            } => {
+                self.channels_skipped += skipped.len();
+                self.attempts.extend(
+                    attempts
+                        .into_iter()
+                        .map(|(channel, n)| (package.name.clone(), channel, n)),
+                );
+                failed
+            }
+            FetchOutcome::Skipped => {
+                self.skipped += 1;
+                self.channels_skipped += enabled;
+                vec![]
+            }
+            FetchOutcome::Failed { failed, .. } => {
+                self.failed += 1;
+                // the rest of a failed package's channels were skipped
+                self.channels_skipped += enabled.saturating_sub(failed.len());
+                failed
+            }
+        };
+
+        self.failures.extend(
+            failed
+                .into_iter()
+                .map(|(channel, cause)| (package.name.clone(), channel, cause)),
+        );
+    }
+
+    /// Group failed channels by the kind of failure
+    pub fn failure_kinds(&self) -> IndexMap<String, Vec<String>> {
+        let mut kinds = IndexMap::<_, Vec<_>>::new();
+        for (package, channel, cause) in &self.failures {
+            kinds.entry(cause.kind()).or_default().push(format!("{package} ({channel})"));
+        }
+        kinds.sort_by(|_, a, _, b| b.len().cmp(&a.len()));
+        kinds
+    }
+
+    /// Write statistics to the cache, where scripts like commit.sh can find them
+    pub fn write(&self) -> Result<()> {
+        fs::write(VAT_CACHE.join("total"), self.total.to_string())?;
+        fs::write(VAT_CACHE.join("failed"), self.failed.to_string())?;
+        fs::write(VAT_CACHE.join("skipped"), self.skipped.to_string())?;
+        fs::write(VAT_CACHE.join("checked"), self.checked().to_string())?;
+        fs::write(VAT_CACHE.join("channels_total"), self.channels_total.to_string())?;
+        fs::write(VAT_CACHE.join("channels_skipped"), self.channels_skipped.to_string())?;
+        fs::write(VAT_CACHE.join("channels_failed"), self.failures.len().to_string())?;
+        fs::write(VAT_CACHE.join("channels_checked"), self.channels_checked().to_string())?;
+
+        let mut failures = String::new();
+        for (package, channel, cause) in &self.failures {
+            let _ = writeln!(failures, "{package}\t{channel}\t{}", cause.kind());
+        }
+        fs::write(VAT_CACHE.join("failures"), failures)?;
+
+        let mut attempts = String::new();
+        for (package, channel, n) in &self.attempts {
+            let _ = writeln!(attempts, "{package}\t{channel}\t{n}");
+        }
+        fs::write(VAT_CACHE.join("attempts"), attempts)?;
+
+        let mut tripped = String::new();
+        for host in &self.tripped {
+            let _ = writeln!(tripped, "{host}");
+        }
+        fs::write(VAT_CACHE.join("tripped"), tripped)?;
+
+        Ok(())
+    }
+
+    pub fn summarize(&self) {
+        info!(
+            "Checked {} of {} packages ({} skipped, {} failed)",
+            self.checked(),
+            self.total,
+            self.skipped,
+            self.failed
+        );
+        info!(
+            "Checked {} of {} channels ({} skipped, {} failed)",
+            self.channels_checked(),
+            self.channels_total,
+            self.channels_skipped,
+            self.failures.len()
+        );
+
+        for (kind, packages) in self.failure_kinds() {
+            info!("    - {kind} ({}): {}", packages.len(), packages.join(", "));
+        }
+
+        let retried = self.attempts.iter().filter(|(.., n)| *n > 1).count();
+        if retried > 0 {
+            info!("Channels fetched after retrying: {retried}");
+        }
+
+        if !self.tripped.is_empty() {
+            warn!("Hosts found unavailable: {}", self.tripped.join(", "));
+        }
+    }
+}
+
+/// # Pick the versions to write for a package given its fetch outcome
+///
+/// Skipped packages fall back to their previous versions, and failed packages keep them marked
+/// stale. A package without any, like a newly added one that failed, is left out of the database
+/// entirely.
+fn resolve_versions(package: &Package, outcome: &FetchOutcome) -> Option<Vec<VersionChannel>> {
+    match outcome {
+        FetchOutcome::Fetched { versions, .. } => return Some(versions.clone()),
+        FetchOutcome::Skipped => {
+            debug!("Skipped fetching versions for package '{}'", package.name);
+        }
+        FetchOutcome::Failed { versions, failed } => {
+            for (channel, cause) in failed {
+                error!("Failed to fetch versions for {} ({channel}): {cause}", package.name);
+            }
+            if !versions.is_empty() {
+                return Some(versions.clone());
+            }
+        }
+    }
+
+    package
+        .read_versions()
+        .inspect_err(|e| error!("No old versions to fall back on for '{}': {e}", package.name))
+        .ok()
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The hosts a package's enabled channels fetch from
+fn hosts(package: &Package) -> Vec<String> {
+    package
+        .config
+        .channels
+        .iter()
+        .filter(|c| c.enabled)
+        .filter_map(|c| throttle::host(&c.upstream(package)))
+        .collect()
+}
+
+/// # Take the next package to fetch
+///
+/// Packages whose hosts are all ready go first, so a throttled host doesn't hold up the rest. If
+/// none are ready, this waits for a fetch to finish or a bucket to refill.
+fn next<'a>(queue: &Mutex<VecDeque<&'a Package>>) -> Option<&'a Package> {
+    loop {
+        let mut wait = MAX_WAIT;
+        {
+            let mut queue = lock(queue);
+            if queue.is_empty() {
+                return None;
+            }
+
+            // fetches from tripped hosts are short-circuited, so the throttle doesn't matter
+            let ready = queue.iter().position(|package| {
+                hosts(package).iter().all(|host| {
+                    if BREAKER.is_open(host) {
+                        return true;
+                    }
+                    match THROTTLE.ready(host) {
+                        Ok(()) => true,
+                        Err(Busy::Limited(until)) => {
+                            wait = wait.min(until);
+                            false
+                        }
+                        Err(Busy::Full) => false,
+                    }
+                })
+            });
+            if let Some(i) = ready {
+                return queue.remove(i);
+            }
+        }
+
+        THROTTLE.wait(wait);
+    }
+}
+
 pub fn fetch_all(packages: &[Package]) -> Result<IndexMap<Package, Vec<VersionChannel>>> {
     let threads = env::var("RAYON_NUM_THREADS")
         .ok()
@@ -43,62 +274,34 @@ pub fn fetch_all(packages: &[Package]) -> Result<IndexMap<Package, Vec<VersionCh
         .build()
         .expect("Failed to create thread pool");
 
-    let res = pool
-        .install(|| {
-            packages
-                .par_iter()
-                .map(|package| {
-                    let mut skipped = 0;
-                    let mut failed = 0;
-
-                    let versions = match package.fetch() {
-                        Ok(v) => v,
-                        Err(e) if e.to_string().contains("Tails!") => {
-                            skipped = 1;
-                            debug!("Skipped fetching versions for package '{}'", package.name);
-                            package.read_versions().wrap_err_with(|| {
-                                format!(
-                                    "Failed to read old versions for skipped package '{}'",
-                                    package.name
-                                )
-                            })?
-                        }
-                        Err(e) => {
-                            failed = 1;
-                            error!("Failed to fetch versions for {}: {e}", package.name);
-                            package.read_versions().wrap_err_with(|| {
-                                format!(
-                                    "Failed to read old versions for failed package '{}'",
-                                    package.name
-                                )
-                            })?
-                        }
-                    };
+    info!("Using seed {}", *schedule::SEED);
 
-                    Ok::<_, Error>((package.clone(), versions, skipped, failed))
-                })
-                .collect::<Result<Vec<_>, _>>()
-        })
-        .wrap_err("Failed to bulk fetch versions")?;
+    let queue = Mutex::new(packages.iter().collect::<VecDeque<_>>());
+    let results = Mutex::new(Vec::with_capacity(packages.len()));
+
+    pool.broadcast(|_| {
+        while let Some(package) = next(&queue) {
+            let outcome = package.fetch();
+            let versions = resolve_versions(package, &outcome);
+            lock(&results).push((package.clone(), versions, outcome));
+        }
+    });
+
+    let res = results.into_inner().unwrap_or_else(PoisonError::into_inner);
 
     let mut map = IndexMap::new();
-    let mut skipped_count = 0;
-    let mut failed_count = 0;
-
-    for (pkg, ver, skipped, failed) in res {
-        map.insert(pkg, ver);
-        skipped_count += skipped;
-        failed_count += failed;
-    }
-
-    let total = map.len();
-    fs::write(VAT_CACHE.join("total"), total.to_string())?;
-    fs::write(VAT_CACHE.join("failed"), failed_count.to_string())?;
-    fs::write(VAT_CACHE.join("skipped"), skipped_count.to_string())?;
-    fs::write(
-        VAT_CACHE.join("checked"),
-        (total - failed_count - skipped_count).to_string(),
-    )?;
+    let mut stats = RunStats::default();
+
+    for (pkg, ver, outcome) in res {
+        stats.record(&pkg, outcome);
+        if let Some(ver) = ver {
+            map.insert(pkg, ver);
+        }
+    }
+
+    stats.tripped = BREAKER.tripped();
+    stats.write()?;
+    stats.summarize();
     map.sort_keys();
 
     Ok(map)
@@ -135,6 +338,8 @@ pub fn write_all(map: &IndexMap<Package, Vec<VersionChannel>>) -> Result<()> {
 #[allow(clippy::unwrap_used)]
 mod tests {
     use super::*;
+    use crate::package::PackageConfig;
+    use pretty_assertions::assert_eq;
 
     #[test]
     fn find_packages() {
@@ -154,4 +359,74 @@ mod tests {
         assert!(all.iter().any(|p| p.name == "py/build"));
         assert!(all.iter().all(|p| p.name != "py"));
     }
+
+    #[test]
+    fn failed_without_fallback() {
+        let package = Package {
+            name: "zz-nonexistent".into(),
+            config: PackageConfig::default(),
+        };
+        let outcome = FetchOutcome::Failed {
+            versions: vec![],
+            failed: vec![("release".into(), FetchError::EmptyStdout)],
+        };
+
+        assert!(resolve_versions(&package, &outcome).is_none());
+
+        let mut stats = RunStats::default();
+        stats.record(&package, outcome);
+        assert_eq!(stats.failed, 1);
+        assert_eq!(stats.failures.len(), 1);
+        assert_eq!(stats.checked(), 0);
+    }
+
+    #[test]
+    fn failed_keeps_stale() {
+        let package = Package {
+            name: "zz-nonexistent".into(),
+            config: PackageConfig::default(),
+        };
+        let stale = VersionChannel {
+            channel: "release".into(),
+            version: "1.0".into(),
+            stale: true,
+        };
+        let outcome = FetchOutcome::Failed {
+            versions: vec![stale],
+            failed: vec![("release".into(), FetchError::HostUnavailable("example.org".into()))],
+        };
+
+        let versions = resolve_versions(&package, &outcome).unwrap();
+        assert_eq!(versions.len(), 1);
+        assert_eq!(versions[0].version, "1.0");
+        assert!(versions[0].stale);
+    }
+
+    #[test]
+    fn partial_failure() {
+        let package = Package {
+            name: "zz-nonexistent".into(),
+            config: PackageConfig::default(),
+        };
+        let outcome = FetchOutcome::Fetched {
+            versions: vec![VersionChannel {
+                channel: "release".into(),
+                version: "1.0".into(),
+                stale: false,
+            }],
+            skipped: vec![],
+            failed: vec![("commit".into(), FetchError::Timeout)],
+            attempts: vec![("release".into(), 2)],
+        };
+
+        let mut stats = RunStats::default();
+        stats.record(&package, outcome);
+        assert_eq!(stats.failed, 0);
+        assert_eq!(stats.checked(), 1);
+        assert_eq!(stats.attempts, [("zz-nonexistent".into(), "release".into(), 2)]);
+        assert_eq!(
+            stats.failure_kinds().get("timeout"),
+            Some(&vec!["zz-nonexistent (commit)".to_string()])
+        );
+    }
 }
@@ -0,0 +1,292 @@
+// package/explain.rs
+//! Show how a channel's fetch arrives at its version
+//!
+//! Each stage of a bash `fetch` is run on its own, as the prefix of the pipeline up to and
+//! including it, in the same environment as a real fetch. Pipelines run step by step instead.
+
+use std::fmt::Write;
+
+use color_eyre::Result;
+use color_eyre::eyre::eyre;
+use regex::Regex;
+
+use super::outcome::FetchError;
+use super::pipeline;
+use super::{Package, PackageChannel};
+use crate::utils::str::basename;
+use crate::utils::ver::Version;
+
+/// Lines shown per stage, unless all are asked for
+const TAIL: usize = 10;
+
+/// # A stage of a fetch, with its output so far
+pub struct Stage {
+    pub label: String,
+    pub output: Result<Vec<String>, FetchError>,
+}
+
+/// # Explain a package's channels, or a single one
+pub fn report(package: &Package, channel: Option<&str>, all: bool) -> Result<String> {
+    let channels = match channel {
+        Some(name) => vec![
+            package
+                .get_channel(name)
+                .ok_or_else(|| eyre!("No channel '{name}' for package '{}'", package.name))?,
+        ],
+        None => package.config.channels.iter().collect(),
+    };
+
+    let mut s = String::new();
+    for (i, channel) in channels.into_iter().enumerate() {
+        if i > 0 {
+            s.push('\n');
+        }
+        explain(&mut s, package, channel, all);
+    }
+    Ok(s)
+}
+
+fn explain(s: &mut String, package: &Package, channel: &PackageChannel, all: bool) {
+    let _ = writeln!(s, "{}/{}", package.name, channel.name);
+    let _ = writeln!(s, "upstream: {}", channel.upstream(package));
+    if !channel.enabled {
+        let _ = writeln!(s, "disabled");
+    }
+
+    let stages = stages(package, channel);
+
+    let mut previous = None;
+    for (i, stage) in stages.iter().enumerate() {
+        let _ = write!(s, "\n[{}] {}", i + 1, stage.label);
+
+        let lines = match &stage.output {
+            Ok(lines) => lines,
+            Err(e) => {
+                let _ = writeln!(s, "\n    error: {e}");
+                break;
+            }
+        };
+
+        let _ = write!(s, " ({} lines", lines.len());
+        match previous {
+            Some(n) if lines.len() < n => {
+                let _ = write!(s, ", {} dropped", n - lines.len());
+            }
+            Some(n) if lines.len() > n => {
+                let _ = write!(s, ", {} added", lines.len() - n);
+            }
+            _ => {}
+        }
+        s.push(')');
+        if lines.is_empty() && previous.is_some_and(|n| n > 0) {
+            s.push_str(" <- every line disappeared here");
+        }
+        s.push('\n');
+
+        let shown = if all {
+            lines.len()
+        } else {
+            TAIL.min(lines.len())
+        };
+        if shown < lines.len() {
+            let _ = writeln!(s, "    ... {} earlier lines", lines.len() - shown);
+        }
+        for line in &lines[lines.len() - shown..] {
+            let _ = writeln!(s, "    {line}");
+        }
+
+        previous = Some(lines.len());
+    }
+
+    s.push('\n');
+    let Some(Ok(output)) = stages.last().map(|stage| &stage.output) else {
+        let _ = writeln!(s, "version: none, the fetch failed");
+        return;
+    };
+    if output.iter().all(String::is_empty) {
+        let _ = writeln!(s, "version: none, the fetch had no output");
+        return;
+    }
+
+    let mut version = Version::new(output.join("\n"));
+    version.trim(package);
+    let _ = writeln!(s, "version: {}", version.fmt);
+
+    if let Some(expected) = &channel.expected {
+        let result = match Regex::new(expected) {
+            Ok(re) if re.is_match(&version.fmt) => "matched",
+            Ok(_) => "did not match",
+            Err(_) => "invalid regex",
+        };
+        let _ = writeln!(s, "expected: {expected} ({result})");
+    }
+}
+
+/// Run the stages of a channel's fetch, stopping at the first error
+fn stages(package: &Package, channel: &PackageChannel) -> Vec<Stage> {
+    if let Some(steps) = &channel.pipeline {
+        let upstream = channel.upstream(package);
+        let ctx = pipeline::Context {
+            name: basename(&package.name),
+            upstream: &upstream,
+            input: None,
+        };
+
+        let mut stages = Vec::<Stage>::new();
+        let mut lines = vec![];
+        for step in steps {
+            let output = pipeline::apply(step, lines, &ctx);
+            lines = output.as_ref().map_or_else(|_| vec![], Clone::clone);
+
+            let failed = output.is_err();
+            stages.push(Stage {
+                label: label(step),
+                output,
+            });
+            if failed {
+                break;
+            }
+        }
+        return stages;
+    }
+
+    // a native first stage is resolved the way a run would, and the rest is piped its output
+    let (head, rest) = channel.first_stage();
+    let (mut stages, fetch, native) = match channel.native(package, head) {
+        Some(output) => {
+            let native = output.as_ref().ok().cloned();
+            let stage = Stage {
+                label: format!("{head} (native)"),
+                output: output.map(|v| vec![v]),
+            };
+            match (native, rest) {
+                (Some(native), Some(rest)) => (vec![stage], rest, Some(native)),
+                _ => return vec![stage],
+            }
+        }
+        None => (vec![], channel.fetch.as_str(), None),
+    };
+
+    let mut start = 0;
+    for end in split(fetch) {
+        let output = native.as_deref().map_or_else(
+            || channel.bash(package, &fetch[..end]),
+            |native| channel.pipe(package, native, &fetch[..end]),
+        );
+        let output = match output {
+            Err(FetchError::EmptyStdout) => Ok(vec![]),
+            output => output.map(|out| out.lines().map(ToString::to_string).collect()),
+        };
+
+        let stop = output.as_ref().map_or(true, Vec::is_empty);
+        stages.push(Stage {
+            label: fetch[start..end].trim().to_string(),
+            output,
+        });
+        if stop {
+            break;
+        }
+        start = end + 1;
+    }
+    stages
+}
+
+fn label(step: &pipeline::Step) -> String {
+    let json = serde_json::to_string(step).unwrap_or_default();
+    json.trim_matches('"').to_string()
+}
+
+/// Find where each stage of a bash pipeline ends, ignoring pipes that are quoted, escaped,
+/// nested, or part of `||`
+pub(super) fn split(fetch: &str) -> Vec<usize> {
+    let bytes = fetch.as_bytes();
+    let mut ends = vec![];
+    let mut depth = 0_usize;
+    let mut quote = None;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match (quote, bytes[i]) {
+            (Some(b'\''), b'\'') | (Some(b'"'), b'"') => quote = None,
+            (Some(b'"') | None, b'\\') => i += 1,
+            (None, q @ (b'\'' | b'"')) => quote = Some(q),
+            (None, b'(' | b'{') => depth += 1,
+            (None, b')' | b'}') => depth = depth.saturating_sub(1),
+            (None, b'|') if bytes.get(i + 1) == Some(&b'|') => i += 1,
+            (None, b'|') if depth == 0 => ends.push(i),
+            _ => {}
+        }
+        i += 1;
+    }
+
+    ends.push(fetch.len());
+    ends
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn stages(fetch: &str) -> Vec<&str> {
+        let mut start = 0;
+        split(fetch)
+            .into_iter()
+            .map(|end| {
+                let stage = fetch[start..end].trim();
+                start = end + 1;
+                stage
+            })
+            .collect()
+    }
+
+    #[test]
+    fn split_stages() {
+        assert_eq!(
+            stages(r"gr | vtrim -e 's,_,.,g' | grep -Ev '(merge|^0_0$)' | vsort"),
+            [
+                "gr",
+                "vtrim -e 's,_,.,g'",
+                r"grep -Ev '(merge|^0_0$)'",
+                "vsort"
+            ]
+        );
+        assert_eq!(
+            stages(r#"cr | grep "a\"|b" | { head -n1 | cut -f1; } || true"#),
+            ["cr", r#"grep "a\"|b""#, "{ head -n1 | cut -f1; } || true"]
+        );
+        assert_eq!(stages("defgitrelease"), ["defgitrelease"]);
+    }
+
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn native_first_stage() {
+        let mut package = Package {
+            name: "tree".into(),
+            config: toml::from_str(
+                r#"
+                upstream = "https://github.com/a/tree"
+
+                [[channels]]
+                name = "piped"
+                fetch = "ghr | vtrim"
+                expected = ".*"
+
+                [[channels]]
+                name = "or"
+                fetch = "ghr || gr"
+                expected = ".*"
+                "#,
+            )
+            .unwrap(),
+        };
+        package.set_defaults();
+
+        let piped = package.get_channel("piped").unwrap();
+        assert_eq!(piped.first_stage(), ("ghr", Some(" vtrim")));
+        assert_eq!(piped.pipe(&package, "tree-v1_2", " vtrim").unwrap(), "1.2\n");
+
+        let or = package.get_channel("or").unwrap();
+        assert_eq!(or.first_stage(), ("ghr || gr", None));
+    }
+}
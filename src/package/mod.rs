@@ -1,12 +1,18 @@
 // package/mod.rs
 
 pub mod bulk;
+pub mod explain;
+pub mod outcome;
+pub mod pipeline;
+pub mod retry;
+pub mod schedule;
+pub mod stale;
+pub mod test_config;
 
 use color_eyre::Result;
 use color_eyre::eyre::Context;
 use color_eyre::eyre::ContextCompat;
 use color_eyre::eyre::bail;
-use rand::random_range;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
@@ -14,24 +20,39 @@ use std::fmt::Debug;
 use std::fmt::Write;
 use std::fs;
 use std::hash::Hash;
+use std::io;
 use std::path::Component;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::thread;
 
 #[cfg(test)]
 use pretty_assertions::assert_eq;
 
 use crate::CONFIG;
 use crate::NO_CACHE;
+use crate::NO_SANDBOX;
 use crate::SHLIB_PATH;
+use crate::VAT_BIN;
 use crate::VAT_CACHE;
 use crate::VAT_ROOT;
 use crate::args::ARGS;
+use crate::backend::git::{self, GitError};
+use crate::backend::forge::{self, Forge};
+use crate::backend::{go, npm, pypi};
+use crate::utils::breaker::BREAKER;
 use crate::utils::cmd::cmd;
+use crate::utils::fixtures::FIXTURES;
 use crate::utils::float::defloat;
+use crate::utils::sandbox::SANDBOX;
 use crate::utils::str::basename;
+use crate::utils::throttle::{self, THROTTLE};
 use crate::utils::ver::Version;
 
+use self::outcome::{FetchError, FetchOutcome};
+use self::pipeline::Step;
+use self::retry::RetryOverride;
+
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
 pub struct Package {
     pub name: String,
@@ -50,12 +71,15 @@ impl PartialOrd for Package {
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Default, Deserialize, Clone)]
 #[serde(default)]
 pub struct PackageConfig {
     pub upstream: String,
-    pub chance: f64,
+    /// Overrides the adaptive schedule for every channel
+    pub chance: Option<f64>,
     pub channels: Vec<PackageChannel>,
+    /// Samples to test the channels against offline
+    pub tests: Vec<test_config::Test>,
 }
 
 /// Struct to be used when serializing into p/ALL
@@ -65,15 +89,20 @@ pub struct PackageVersions {
     pub versions: Vec<VersionChannel>,
 }
 
-#[derive(Hash, PartialEq, Eq, Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone)]
 #[serde(default)]
 pub struct PackageChannel {
     pub name: String,
     pub enabled: bool,
     pub upstream: Option<String>,
     pub fetch: String,
+    /// Steps run in-process instead of `fetch`
+    pub pipeline: Option<Vec<Step>>,
     pub expected: Option<String>,
-    // TODO: Consider adding per-channel chances
+    /// Overrides the package's chance for this channel
+    pub chance: Option<f64>,
+    /// Overrides the retry policy for this channel
+    pub retry: Option<RetryOverride>,
 }
 
 impl Default for PackageChannel {
@@ -83,82 +112,269 @@ impl Default for PackageChannel {
             enabled: true,
             upstream: None,
             fetch: String::new(),
+            pipeline: None,
             expected: None,
+            chance: None,
+            retry: None,
         }
     }
 }
 
+impl Hash for PackageChannel {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.name.hash(state);
+        self.enabled.hash(state);
+        self.upstream.hash(state);
+        self.fetch.hash(state);
+        self.pipeline.hash(state);
+        self.expected.hash(state);
+        self.chance.map(defloat).hash(state);
+        self.retry.hash(state);
+    }
+}
+
+impl PartialEq for PackageChannel {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.enabled == other.enabled
+            && self.upstream == other.upstream
+            && self.fetch == other.fetch
+            && self.pipeline == other.pipeline
+            && self.expected == other.expected
+            && chance_eq(self.chance, other.chance)
+            && self.retry == other.retry
+    }
+}
+
+impl Eq for PackageChannel {}
+
 impl PackageChannel {
-    pub fn cmd(&self, package: &Package, command: &[&str]) -> Result<String> {
+    pub fn cmd(
+        &self,
+        package: &Package,
+        command: &[&str],
+        extra_env: &[(&str, &str)],
+    ) -> Result<String, FetchError> {
         let package_root = Package::dir(&package.name);
 
-        let Some(vat_root) = VAT_ROOT.to_str() else {
-            bail!("Invalid Unicode in {}", VAT_ROOT.display());
+        let utf8 = |path: &'static Path| {
+            path.to_str().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid Unicode in {}", path.display()),
+                )
+            })
         };
 
-        let Some(vat_cache) = VAT_CACHE.to_str() else {
-            bail!("Invalid Unicode in {}", VAT_CACHE.display());
-        };
-
-        let Some(shlib_path) = SHLIB_PATH.to_str() else {
-            bail!("Invalid Unicode in {}", SHLIB_PATH.display());
-        };
+        let vat_root = utf8(&VAT_ROOT)?;
+        let vat_cache = utf8(&VAT_CACHE)?;
+        let shlib_path = utf8(&SHLIB_PATH)?;
+        let vat_bin = utf8(&VAT_BIN)?;
 
         let no_cache = NO_CACHE.to_string();
 
-        let upstream = expand_shortform(self.upstream.as_ref().unwrap_or(&package.config.upstream));
+        let upstream = self.upstream(package);
 
-        let env = HashMap::from([
+        let mut env = HashMap::from([
             ("GIT_TERMINAL_PROMPT", "false"),
+            // logs from vat subcommands would end up in the fetch output
+            ("LOG_LEVEL", "off"),
             ("PACKAGE_ROOT", &package_root),
             ("VAT_ROOT", vat_root),
             ("VAT_CACHE", vat_cache),
+            ("VAT_BIN", vat_bin),
             ("SHLIB_PATH", shlib_path),
             ("NO_CACHE", &no_cache),
             ("channel", &self.name),
             ("name", basename(&package.name)),
             ("upstream", &upstream),
         ]);
+        if let Some(fixtures) = FIXTURES.get() {
+            env.insert(fixtures.mode.var(), utf8(&fixtures.dir)?);
+        }
+        env.extend(extra_env.iter().copied());
+
+        let config = CONFIG.get().expect("Config should be initialized");
+        let sandbox = (config.sandbox.enabled && !*NO_SANDBOX).then(|| &*SANDBOX);
+
+        Ok(cmd(command, env, &package_root, sandbox)?)
+    }
+
+    /// Run a fetch command with `sh/lib.env` sourced
+    pub fn bash(&self, package: &Package, fetch: &str) -> Result<String, FetchError> {
+        self.bash_with(package, fetch, &[])
+    }
 
-        cmd(command, env, &package_root)
+    /// Run a fetch command with `sh/lib.env` sourced and extra variables set
+    pub fn bash_with(
+        &self,
+        package: &Package,
+        fetch: &str,
+        env: &[(&str, &str)],
+    ) -> Result<String, FetchError> {
+        let fetch = format!(". {} && {fetch}", SHLIB_PATH.display());
+        self.cmd(package, &["bash", "-c", &fetch], env)
     }
 
-    pub fn fetch(&self, package: &Package) -> Result<String> {
-        let fetch = format!(". {} && {}", SHLIB_PATH.display(), self.fetch);
-        let command = ["bash", "-c", &fetch];
+    /// The expanded upstream for this channel, falling back to the package's
+    pub fn upstream(&self, package: &Package) -> String {
+        expand_shortform(self.upstream.as_ref().unwrap_or(&package.config.upstream))
+    }
+
+    /// Split the fetch into its first stage and the rest of its pipeline, if any
+    pub(super) fn first_stage(&self) -> (&str, Option<&str>) {
+        let end = explain::split(&self.fetch)[0];
+        let (head, rest) = self.fetch.split_at(end);
+        (head.trim(), rest.strip_prefix('|'))
+    }
 
-        let ver = match self.cmd(package, &command) {
-            Err(e) => bail!("{e}"),
-            Ok(v) => v,
+    /// # Fetch a version without going through bash, if there's a native backend for this channel
+    ///
+    /// A native fetch piped into more commands, like `ghr | vtrim`, is resolved natively and its
+    /// output piped into the rest, so it still gets credentials the sandbox doesn't pass on.
+    fn fetch_native(&self, package: &Package) -> Option<Result<String, FetchError>> {
+        let (head, rest) = self.first_stage();
+        let output = self.native(package, head)?;
+
+        Some(match rest {
+            Some(rest) => output.and_then(|out| self.pipe(package, &out, rest)),
+            None => output,
+        })
+    }
+
+    /// Run the rest of a fetch's pipeline on the output of its first stage
+    pub(super) fn pipe(
+        &self,
+        package: &Package,
+        output: &str,
+        rest: &str,
+    ) -> Result<String, FetchError> {
+        let fetch = format!("printf '%s\\n' \"$VAT_OUTPUT\" |{rest}");
+        self.bash_with(package, &fetch, &[("VAT_OUTPUT", output)])
+    }
+
+    /// The output of a native backend for a single fetch function, if there is one
+    pub(super) fn native(&self, package: &Package, fetch: &str) -> Option<Result<String, FetchError>> {
+        let upstream = self.upstream(package);
+
+        match (UpstreamType::from_str(&upstream), fetch) {
+            (UpstreamType::PyPI, "pypiver") => {
+                Some(pypi::latest(&upstream, false).map_err(FetchError::from))
+            }
+            (UpstreamType::PyPI, "pypiunstable") => {
+                Some(pypi::latest(&upstream, true).map_err(FetchError::from))
+            }
+            (UpstreamType::Npm, "npmver") => {
+                Some(npm::latest(&upstream, false).map_err(FetchError::from))
+            }
+            (UpstreamType::Npm, "npmunstable") => {
+                Some(npm::latest(&upstream, true).map_err(FetchError::from))
+            }
+            (UpstreamType::Go, "gover") => Some(go::latest(&upstream, false).map_err(FetchError::from)),
+            (UpstreamType::Go, "gounstable") => {
+                Some(go::latest(&upstream, true).map_err(FetchError::from))
+            }
+            (UpstreamType::Forge, "forgerelease" | "ghr") => {
+                Some(forge::latest_release(&upstream).map_err(FetchError::from))
+            }
+            (UpstreamType::Forge, "forgeunstable") => {
+                Some(forge::latest_unstable(&upstream).map_err(FetchError::from))
+            }
+            (UpstreamType::Forge, "forgecommit" | "ghc") => {
+                Some(forge::latest_commit(&upstream).map_err(FetchError::from))
+            }
+            (UpstreamType::Git | UpstreamType::Forge, "defgitcommit") => Some(
+                git::cached_refs(&upstream)
+                    .and_then(|refs| {
+                        refs.head()
+                            .map(|head| head.commit().to_string())
+                            .ok_or(GitError::MissingHead)
+                    })
+                    .map_err(FetchError::from),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Fetch the raw output for a version, through whichever of the pipeline, a native backend,
+    /// or bash applies
+    fn fetch_raw(&self, package: &Package) -> Result<String, FetchError> {
+        if let Some(steps) = &self.pipeline {
+            let upstream = self.upstream(package);
+            let ctx = pipeline::Context {
+                name: basename(&package.name),
+                upstream: &upstream,
+                input: None,
+            };
+            return pipeline::run(steps, &ctx);
+        }
+
+        self.fetch_native(package)
+            .unwrap_or_else(|| self.bash(package, &self.fetch))
+    }
+
+    /// Make a single attempt at a fetch, within the limits for its host
+    fn attempt(&self, package: &Package) -> Result<String, FetchError> {
+        let Some(host) = throttle::host(&self.upstream(package)) else {
+            return self.fetch_raw(package);
+        };
+
+        if !BREAKER.allow(&host) {
+            return Err(FetchError::HostUnavailable(host));
+        }
+
+        let _permit = THROTTLE.acquire(&host);
+        let ver = self.fetch_raw(package);
+        BREAKER.record(&host, ver.as_ref().is_err_and(FetchError::is_connection_failure));
+        ver
+    }
+
+    /// # Fetch a channel's version, along with the attempts it took
+    ///
+    /// Transient failures are retried with backoff, per the channel's retry policy.
+    pub fn fetch(&self, package: &Package) -> Result<(String, u32), FetchError> {
+        let policy = retry::policy(self.retry.as_ref());
+
+        let mut attempts = 1;
+        let ver = loop {
+            match self.attempt(package) {
+                Err(e) if e.is_transient() && attempts < policy.attempts => {
+                    let delay = retry::delay(&policy, attempts);
+                    debug!(
+                        "Retrying {} for package '{}' in {delay:?} after attempt {attempts} failed: {e}",
+                        self.name, package.name
+                    );
+                    thread::sleep(delay);
+                    attempts += 1;
+                }
+                ver => break ver?,
+            }
         };
 
         let mut version = Version::new(ver);
         version.trim(package);
         let v = version.fmt;
 
-        if let Some(re) = &self.expected {
-            let re = match Regex::from_str(re) {
-                Ok(re) => re,
-                Err(e) => {
-                    error!("Invalid expected regex '{re}': {e}");
-                    bail!("Invalid expected regex");
-                }
-            };
+        if let Some(expected) = &self.expected {
+            let re = Regex::from_str(expected)
+                .map_err(|e| FetchError::InvalidRegex(expected.clone(), e))?;
 
             if !re.is_match(&v) {
-                error!("Version '{v}' does not match expected '{re}'");
-                bail!("Version does not match expected");
+                return Err(FetchError::ExpectedMismatch {
+                    version: v,
+                    expected: expected.clone(),
+                });
             }
         }
 
-        Ok(v)
+        Ok((v, attempts))
     }
 }
 
 impl Hash for PackageConfig {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.upstream.hash(state);
-        defloat(self.chance).hash(state);
+        self.chance.map(defloat).hash(state);
         self.channels.hash(state);
     }
 }
@@ -166,7 +382,7 @@ impl Hash for PackageConfig {
 impl PartialEq for PackageConfig {
     fn eq(&self, other: &Self) -> bool {
         self.upstream == other.upstream
-            && (self.chance - other.chance).abs() < 0.01
+            && chance_eq(self.chance, other.chance)
             && self.channels == other.channels
     }
 }
@@ -177,24 +393,23 @@ impl Eq for PackageConfig {}
 pub struct VersionChannel {
     pub channel: String,
     pub version: String,
+    /// Whether the last fetch failed, leaving the version as it was
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub stale: bool,
 }
 
-impl Default for PackageConfig {
-    fn default() -> Self {
-        Self {
-            upstream: String::new(),
-            chance: 1.0,
-            channels: vec![],
-        }
-    }
-}
-
+#[derive(Clone, Copy)]
 pub enum UpstreamType {
     Arch,
     CratesIO,
     Curl,
     Empty,
     Git,
+    PyPI,
+    Npm,
+    Go,
+    /// A git repository on a forge with a release API
+    Forge,
 }
 
 impl UpstreamType {
@@ -206,11 +421,22 @@ impl UpstreamType {
             // match crates.io
             s if s.starts_with("https://crates.io/crates/") => Self::CratesIO,
 
+            // match pypi
+            s if s.contains("pypi.org/project/") => Self::PyPI,
+
+            // match npm and the go module proxy, including configured mirrors
+            s if npm::package(s).is_some() => Self::Npm,
+            s if go::module(s).is_some() => Self::Go,
+
             // match (sorted) distfile pages
             s if s.contains("C=M") && s.contains("O=D") => Self::Curl,
+            s if s.starts_with("ftp://") => Self::Curl,
 
             "" => Self::Empty,
 
+            // match forges with a release api
+            s if Forge::detect(s).is_some() => Self::Forge,
+
             // assume all else is git
             _ => Self::Git,
         }
@@ -304,20 +530,40 @@ impl Package {
             let upstream = expand_shortform(channel.upstream.as_ref().unwrap_or(&self.config.upstream));
             let ut = UpstreamType::from_str(&upstream);
 
-            if channel.fetch.is_empty() {
+            if channel.pipeline.is_some() {
+                assert!(
+                    channel.fetch.is_empty(),
+                    "Invalid config in {}: Both fetch and pipeline for {}",
+                    self.name,
+                    channel.name
+                );
+            } else if channel.fetch.is_empty() {
                 channel.fetch = match (ut, channel.name.as_str()) {
                     (UpstreamType::Arch, "release") => "archver".into(),
                     (UpstreamType::CratesIO, "release") => "cratesiover".into(),
 
+                    (UpstreamType::PyPI, "release") => "pypiver".into(),
+                    (UpstreamType::PyPI, "unstable") => "pypiunstable".into(),
+
+                    (UpstreamType::Npm, "release") => "npmver".into(),
+                    (UpstreamType::Npm, "unstable") => "npmunstable".into(),
+
+                    (UpstreamType::Go, "release") => "gover".into(),
+                    (UpstreamType::Go, "unstable") => "gounstable".into(),
+
                     (UpstreamType::Curl, "release") => "defcurlrelease".into(),
                     (UpstreamType::Curl, "unstable") => "defcurlunstable".into(),
                     (UpstreamType::Curl, "commit") => "defcurlcommit".into(),
 
                     (UpstreamType::Empty, _) => String::new(),
 
+                    // forges flag their prereleases, so their release APIs beat guessing from tags
+                    (UpstreamType::Forge, "release") => "forgerelease".into(),
+                    (UpstreamType::Forge, "unstable") => "forgeunstable".into(),
+
                     (UpstreamType::Git, "release") => "defgitrelease".into(),
                     (UpstreamType::Git, "unstable") => "defgitunstable".into(),
-                    (UpstreamType::Git, "commit") => "defgitcommit".into(),
+                    (UpstreamType::Git | UpstreamType::Forge, "commit") => "defgitcommit".into(),
 
                     _ => panic!(
                         "Invalid config in {}: Missing fetch for {}",
@@ -327,13 +573,17 @@ impl Package {
             }
 
             if channel.expected.is_none() {
-                channel.expected = match channel.name.as_str() {
-                    "release" => Some(r"^[0-9]+(\.[0-9]+)*$".into()),
-                    "unstable" => {
+                channel.expected = match (ut, channel.name.as_str()) {
+                    // semver pre-releases, like "5.5.0-beta.2"
+                    (UpstreamType::Npm | UpstreamType::Go, "unstable") => {
+                        Some(r"^[0-9]+(\.[0-9]+)*(-[0-9a-z.]+)?$".into())
+                    }
+                    (_, "release") => Some(r"^[0-9]+(\.[0-9]+)*$".into()),
+                    (_, "unstable") => {
                         Some(r"^[0-9]+(\.[0-9]+)*-?(rc|alpha|beta|a|b|pre|dev)?[0-9]*$".into())
                     }
-                    "commit" => Some(r"^[0-9a-f]{40}$".into()),
-                    n if n.parse::<u64>().is_ok() => Some(format!(r"^{n}(\.[0-9]+)*$")),
+                    (_, "commit") => Some(r"^[0-9a-f]{40}$".into()),
+                    (_, n) if n.parse::<u64>().is_ok() => Some(format!(r"^{n}(\.[0-9]+)*$")),
 
                     _ => panic!(
                         "Invalid config in {}: Missing expected for {}",
@@ -369,43 +619,113 @@ impl Package {
     }
 
     /// Used for log output only
-    pub fn format_fetched(&self, version_channels: &[VersionChannel]) -> String {
+    pub fn format_fetched(&self, version_channels: &[VersionChannel], skipped: &[String]) -> String {
         let mut s = String::new();
         let _ = writeln!(&mut s, "Fetched versions for {}", self.name);
         for vc in version_channels {
-            let _ = writeln!(&mut s, "        - {}: {}", vc.channel, vc.version);
+            let note = if skipped.contains(&vc.channel) {
+                " (skipped)"
+            } else if vc.stale {
+                " (stale)"
+            } else {
+                ""
+            };
+            let _ = writeln!(&mut s, "        - {}: {}{note}", vc.channel, vc.version);
         }
         s
     }
 
-    pub fn fetch(&self) -> Result<Vec<VersionChannel>> {
+    pub fn fetch(&self) -> FetchOutcome {
+        let previous = self.read_versions().unwrap_or_default();
+
         // if fallback versions don't exist, or --guarantee is passed, guarantee a fetch
         let should_guarantee = ARGS.guarantee || !self.has_fallback_versions();
 
-        if self.config.chance < 1.0
-            && !should_guarantee
-            && random_range(0.0..=1.0) > self.config.chance
-        {
-            bail!("Tails!")
-        }
+        // channels without their own chance share a single roll for the package's chance, and
+        // without either the chance comes from the adaptive schedule
+        let package_roll = self
+            .config
+            .chance
+            .map(|chance| should_guarantee || schedule::roll(chance, &self.name));
 
         let mut version_channels = vec![];
-        for channel in &self.config.channels {
-            if channel.enabled {
-                version_channels.push(VersionChannel {
-                    channel: channel.name.clone(),
-                    version: channel.fetch(self)?,
-                });
+        let mut skipped = vec![];
+        let mut failed = vec![];
+        let mut attempts = vec![];
+        for channel in self.config.channels.iter().filter(|c| c.enabled) {
+            let old = previous.iter().find(|vc| vc.channel == channel.name);
+            let key = format!("{}/{}", self.name, channel.name);
+
+            let should_check = match (old, channel.chance, package_roll) {
+                (None, ..) => true,
+                _ if should_guarantee => true,
+                (Some(_), Some(chance), _) => schedule::roll(chance, &key),
+                (Some(_), None, Some(package_roll)) => package_roll,
+                (Some(_), None, None) => {
+                    schedule::roll(schedule::chance(self, &channel.name), &key)
+                }
+            };
+
+            if let (false, Some(old)) = (should_check, old) {
+                debug!("Skipped fetching {} for package '{}'", channel.name, self.name);
+                version_channels.push(old.clone());
+                skipped.push(channel.name.clone());
+                continue;
+            }
+
+            match channel.fetch(self) {
+                Ok((version, n)) => {
+                    version_channels.push(VersionChannel {
+                        channel: channel.name.clone(),
+                        version,
+                        stale: false,
+                    });
+                    attempts.push((channel.name.clone(), n));
+                }
+                Err(cause) => {
+                    // keep the last known version, if any, so the other channels still update
+                    if let Some(old) = old {
+                        version_channels.push(VersionChannel {
+                            stale: true,
+                            ..old.clone()
+                        });
+                    }
+                    failed.push((channel.name.clone(), cause));
+                }
             }
         }
 
-        info!("{}", self.format_fetched(&version_channels));
+        let fetched = version_channels
+            .iter()
+            .filter(|vc| !vc.stale && !skipped.contains(&vc.channel))
+            .count();
+        if fetched == 0 && !failed.is_empty() {
+            return FetchOutcome::Failed {
+                versions: version_channels,
+                failed,
+            };
+        }
+
+        if !skipped.is_empty() && skipped.len() == version_channels.len() {
+            return FetchOutcome::Skipped;
+        }
+
+        for (channel, cause) in &failed {
+            warn!("Failed to fetch {channel} for package '{}': {cause}", self.name);
+        }
+
+        info!("{}", self.format_fetched(&version_channels, &skipped));
         debug!(
             "Versions as JSON: {}",
-            serde_json::to_string_pretty(&version_channels)?
+            serde_json::to_string_pretty(&version_channels).unwrap_or_default()
         );
 
-        Ok(version_channels)
+        FetchOutcome::Fetched {
+            versions: version_channels,
+            skipped,
+            failed,
+            attempts,
+        }
     }
 
     pub fn get_package_path(&self) -> PathBuf {
@@ -446,6 +766,13 @@ impl Package {
     }
 }
 
+fn chance_eq(a: Option<f64>, b: Option<f64>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => (a - b).abs() < 0.01,
+        (a, b) => a.is_none() && b.is_none(),
+    }
+}
+
 fn expand_shortform<S: Into<String>>(upstream: S) -> String {
     let upstream = upstream.into();
     let shortforms = CONFIG.get().expect("Config should be initialized").shortforms.as_slice();
@@ -0,0 +1,221 @@
+// package/outcome.rs
+
+use std::io;
+
+use thiserror::Error;
+
+use super::VersionChannel;
+use crate::backend::git::GitError;
+use crate::backend::forge::ForgeError;
+use crate::backend::go::GoError;
+use crate::backend::npm::NpmError;
+use crate::backend::pypi::PyPIError;
+use crate::utils::cmd::{CmdError, UpstreamError};
+use crate::utils::http::HttpError;
+
+/// # The result of checking a package
+#[derive(Debug)]
+pub enum FetchOutcome {
+    Fetched {
+        versions: Vec<VersionChannel>,
+        /// Channels that weren't checked and kept their previous version
+        skipped: Vec<String>,
+        /// Channels that failed and kept their previous version, if any, marked stale
+        failed: Vec<(String, FetchError)>,
+        /// Channels that were fetched, with the attempts each took
+        attempts: Vec<(String, u32)>,
+    },
+
+    /// None of the package's channels were checked this run
+    Skipped,
+
+    /// None of the package's channels were fetched successfully
+    Failed {
+        /// Previous versions, with the failed channels marked stale
+        versions: Vec<VersionChannel>,
+        failed: Vec<(String, FetchError)>,
+    },
+}
+
+/// # Why a channel failed to fetch
+#[derive(Error, Debug)]
+pub enum FetchError {
+    #[error(transparent)]
+    Upstream(UpstreamError),
+
+    #[error("timed out")]
+    Timeout,
+
+    #[error("host unavailable: {0}")]
+    HostUnavailable(String),
+
+    #[error("no fixture for {0}")]
+    NoFixture(String),
+
+    #[error("no sample {0} to test with")]
+    MissingInput(&'static str),
+
+    #[error("exited with nonzero status: {0}")]
+    NonzeroStatus(i32),
+
+    #[error("output in stderr: {}", .0.trim())]
+    StderrOutput(String),
+
+    #[error("no output in stdout")]
+    EmptyStdout,
+
+    /// Something the command started, like a backgrounded process, kept its output open
+    #[error("output pipes held open after exiting")]
+    PipesHeldOpen,
+
+    #[error("version '{version}' does not match expected '{expected}'")]
+    ExpectedMismatch { version: String, expected: String },
+
+    #[error("invalid expected regex '{0}'")]
+    InvalidRegex(String, #[source] regex::Error),
+
+    #[error(transparent)]
+    Git(GitError),
+
+    #[error(transparent)]
+    Forge(ForgeError),
+
+    #[error(transparent)]
+    PyPI(PyPIError),
+
+    #[error(transparent)]
+    Npm(NpmError),
+
+    #[error(transparent)]
+    Go(GoError),
+
+    #[error("io error")]
+    Io(#[from] io::Error),
+}
+
+impl FetchError {
+    /// A short, stable name for the kind of failure, for use in run statistics
+    pub fn kind(&self) -> String {
+        match self {
+            Self::Upstream(e) => e.kind(),
+            Self::Timeout => "timeout".into(),
+            Self::HostUnavailable(_) => "host-unavailable".into(),
+            Self::NoFixture(_) => "no-fixture".into(),
+            Self::MissingInput(_) => "missing-input".into(),
+            Self::NonzeroStatus(_) => "nonzero-status".into(),
+            Self::StderrOutput(_) => "stderr-output".into(),
+            Self::EmptyStdout => "empty-stdout".into(),
+            Self::PipesHeldOpen => "pipes-held-open".into(),
+            Self::ExpectedMismatch { .. } => "expected-mismatch".into(),
+            Self::InvalidRegex(..) => "invalid-regex".into(),
+            Self::Git(_) => "git".into(),
+            Self::Forge(_) => "forge".into(),
+            Self::PyPI(_) => "pypi".into(),
+            Self::Npm(_) => "npm".into(),
+            Self::Go(_) => "go".into(),
+            Self::Io(_) => "io".into(),
+        }
+    }
+
+    /// Whether the failure might not happen again, so the fetch is worth retrying
+    pub const fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Self::Timeout
+                | Self::Upstream(
+                    UpstreamError::Http(500..=599)
+                        | UpstreamError::ConnectionReset
+                        | UpstreamError::Timeout
+                )
+        )
+    }
+
+    /// Whether the upstream's host couldn't be reached at all, as opposed to answering badly
+    pub const fn is_connection_failure(&self) -> bool {
+        matches!(
+            self,
+            Self::Timeout
+                | Self::Upstream(
+                    UpstreamError::Dns
+                        | UpstreamError::ConnectionRefused
+                        | UpstreamError::ConnectionReset
+                        | UpstreamError::Timeout
+                )
+        )
+    }
+}
+
+impl From<CmdError> for FetchError {
+    fn from(e: CmdError) -> Self {
+        match e {
+            CmdError::Upstream(e) => Self::Upstream(e),
+            CmdError::Timeout => Self::Timeout,
+            CmdError::NonzeroStatus(code) => Self::NonzeroStatus(code),
+            CmdError::StderrOutput(err) => {
+                // from `vat http` and other subcommands, while replaying
+                if let Some(request) = err.trim().strip_prefix("vat: no fixture for ") {
+                    return Self::NoFixture(request.to_string());
+                }
+                Self::StderrOutput(err)
+            }
+            CmdError::EmptyStdout => Self::EmptyStdout,
+            CmdError::PipesHeldOpen => Self::PipesHeldOpen,
+            CmdError::Io(e) => Self::Io(e),
+        }
+    }
+}
+
+impl From<HttpError> for FetchError {
+    fn from(e: HttpError) -> Self {
+        if let HttpError::NoFixture(request) = e {
+            return Self::NoFixture(request);
+        }
+        UpstreamError::from_http(&e).map_or_else(|| Self::Io(io::Error::other(e)), Self::Upstream)
+    }
+}
+
+impl From<GitError> for FetchError {
+    fn from(e: GitError) -> Self {
+        match e {
+            GitError::Http(e) => e.into(),
+            e => Self::Git(e),
+        }
+    }
+}
+
+impl From<ForgeError> for FetchError {
+    fn from(e: ForgeError) -> Self {
+        match e {
+            ForgeError::Http(e) => e.into(),
+            ForgeError::RateLimited => Self::Upstream(UpstreamError::RateLimited),
+            e => Self::Forge(e),
+        }
+    }
+}
+
+impl From<PyPIError> for FetchError {
+    fn from(e: PyPIError) -> Self {
+        match e {
+            PyPIError::Http(e) => e.into(),
+            e => Self::PyPI(e),
+        }
+    }
+}
+
+impl From<NpmError> for FetchError {
+    fn from(e: NpmError) -> Self {
+        match e {
+            NpmError::Http(e) => e.into(),
+            e => Self::Npm(e),
+        }
+    }
+}
+
+impl From<GoError> for FetchError {
+    fn from(e: GoError) -> Self {
+        match e {
+            GoError::Http(e) => e.into(),
+            e => Self::Go(e),
+        }
+    }
+}
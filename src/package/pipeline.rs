@@ -0,0 +1,448 @@
+// package/pipeline.rs
+//! In-process fetch pipelines
+//!
+//! A channel's `pipeline` is an alternative to its `fetch` that doesn't need bash. Each step is
+//! either a source, which replaces the lines so far, or a filter implemented after its
+//! counterpart in `sh/lib.env`:
+//!
+//! ```toml
+//! pipeline = ["tags", "lowercase", { trim = [["_", "."]] }, "no-leading-letter", "stable", "sort"]
+//! ```
+//!
+//! Like the output of `fetch`, the last line is used as the version.
+
+use std::sync::LazyLock;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use super::outcome::FetchError;
+use crate::backend::git::{self, GitError};
+use crate::backend::listing;
+use crate::utils::http;
+use crate::utils::ver;
+
+/// # A step in a pipeline
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Step {
+    Named(Named),
+    Param(Param),
+}
+
+/// # A step without parameters, written as a string
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Named {
+    /// The upstream's tags, like `gr`
+    Tags,
+    /// The commit of the upstream's HEAD, like `githead`
+    Head,
+    /// The lines of the upstream's body, like `cr`
+    Body,
+    /// The versions of archives in the upstream's directory listing, like `ca`
+    Archives,
+
+    /// `tolower`
+    Lowercase,
+    /// `toupper`
+    Uppercase,
+    /// `vtrim`
+    Trim,
+    /// `vfs`
+    Stable,
+    /// `fsl`
+    NoLeadingLetter,
+    /// `fl`
+    NoLetters,
+    /// `fnd`
+    HasDigit,
+    /// `fdash`
+    NoDash,
+    /// `fdate`
+    NoDate,
+    /// `fdatel`
+    NoDateOnly,
+    /// `fcve`
+    NoCve,
+    /// `vfem`
+    EvenMinor,
+    /// `predash`
+    Predash,
+    /// `commitgrep -o`
+    Commit,
+    /// `vsort`
+    Sort,
+}
+
+/// # A step with parameters, written as a table with a single key
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Param {
+    /// `vtrim`, followed by regex replacements like `vtrim -e 's,_,.,g'`
+    Trim(Vec<(String, String)>),
+    /// `vfs`, with an additional pattern to filter out
+    Stable(String),
+    /// Keep lines matching a regex, like `grep -E`
+    Keep(String),
+    /// Drop lines matching a regex, like `grep -Ev`
+    Drop(String),
+    /// Replace every match of a regex, like `sed -E 's,...,...,g'`
+    Replace(String, String),
+    /// `vrep`
+    Version(VersionMatch),
+}
+
+/// # The options of `vrep`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct VersionMatch {
+    /// The number of parts, where 0 means at least 2
+    pub parts: u8,
+    pub prefix: String,
+    pub suffix: String,
+    /// Output only the match, like `vrepo`
+    pub only: bool,
+}
+
+/// What sources and filters need to know about the channel
+pub struct Context<'a> {
+    pub name: &'a str,
+    pub upstream: &'a str,
+    /// Sample responses that sources read instead of the upstream, while testing
+    pub input: Option<&'a Input>,
+}
+
+/// # Sample responses from an upstream
+#[derive(Debug, Default)]
+pub struct Input {
+    pub tags: Vec<String>,
+    pub head: Option<String>,
+    pub body: Option<String>,
+}
+
+static DATE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"[0-9]{4}-?[0-9]{2}-?[0-9]{2}").expect("Regex should be valid"));
+static DATE_ONLY: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[0-9]{4}-?[0-9]{2}-?[0-9]{2}$").expect("Regex should be valid"));
+static THREE_PARTS: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"[0-9]+\.[0-9]+\.[0-9]+").expect("Regex should be valid"));
+static COMMIT: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"[a-f0-9]{40}").expect("Regex should be valid"));
+
+const UNSTABLE: &str = "rc|dev|beta|alpha|pre";
+const PRE_LABELS: [&str; 5] = ["pre", "rc", "dev", "alpha", "beta"];
+
+/// # Run a pipeline, returning its output like a `fetch` would
+pub fn run(steps: &[Step], ctx: &Context) -> Result<String, FetchError> {
+    let lines = steps
+        .iter()
+        .try_fold(vec![], |lines, step| apply(step, lines, ctx))?;
+
+    if lines.iter().all(|l| l.trim().is_empty()) {
+        return Err(FetchError::EmptyStdout);
+    }
+    Ok(lines.join("\n"))
+}
+
+/// # Apply a single step to the lines so far
+pub fn apply(step: &Step, lines: Vec<String>, ctx: &Context) -> Result<Vec<String>, FetchError> {
+    Ok(match step {
+        Step::Named(Named::Tags) if let Some(input) = ctx.input => input.tags.clone(),
+        Step::Named(Named::Tags) => git::cached_refs(ctx.upstream)?
+            .0
+            .iter()
+            .filter(|r| r.name.starts_with("refs/tags/"))
+            .filter_map(|r| r.name.rsplit('/').next().map(ToString::to_string))
+            .collect(),
+        Step::Named(Named::Head) if let Some(input) = ctx.input => {
+            vec![input.head.clone().ok_or(GitError::MissingHead)?]
+        }
+        Step::Named(Named::Head) => {
+            let refs = git::cached_refs(ctx.upstream)?;
+            let head = refs.head().ok_or(GitError::MissingHead)?;
+            vec![head.commit().to_string()]
+        }
+        Step::Named(Named::Body) => body(ctx)?.lines().map(ToString::to_string).collect(),
+        Step::Named(Named::Archives) => listing::archives(&body(ctx)?, ctx.name)
+            .into_iter()
+            .map(|a| a.version)
+            .collect(),
+
+        Step::Named(Named::Lowercase) => map(lines, |l| l.to_lowercase()),
+        Step::Named(Named::Uppercase) => map(lines, |l| l.to_uppercase()),
+        Step::Named(Named::Trim) => trim(lines, ctx.name, &[])?,
+        Step::Param(Param::Trim(replacements)) => trim(lines, ctx.name, replacements)?,
+        Step::Named(Named::Stable) => drop(lines, &regex(&format!("(?i){UNSTABLE}"))?),
+        Step::Param(Param::Stable(extra)) => {
+            drop(lines, &regex(&format!("(?i){UNSTABLE}|{extra}"))?)
+        }
+        Step::Named(Named::NoLeadingLetter) => {
+            filter(lines, |l| !l.starts_with(|c: char| c.is_ascii_alphabetic()))
+        }
+        Step::Named(Named::NoLetters) => {
+            filter(lines, |l| !l.contains(|c: char| c.is_ascii_alphabetic()))
+        }
+        Step::Named(Named::HasDigit) => filter(lines, |l| l.contains(|c: char| c.is_ascii_digit())),
+        Step::Named(Named::NoDash) => filter(lines, |l| !l.contains('-')),
+        Step::Named(Named::NoDate) => drop(lines, &DATE),
+        Step::Named(Named::NoDateOnly) => drop(lines, &DATE_ONLY),
+        Step::Named(Named::NoCve) => filter(lines, |l| !l.contains("CVE-")),
+        Step::Named(Named::EvenMinor) => filter(lines, |l| is_even_minor(l)),
+        Step::Named(Named::Predash) => map(lines, |l| predash(&l)),
+        Step::Named(Named::Commit) => lines
+            .iter()
+            .flat_map(|l| COMMIT.find_iter(l).map(|m| m.as_str().to_string()))
+            .collect(),
+        Step::Named(Named::Sort) => {
+            let mut lines = lines;
+            ver::sort(&mut lines);
+            lines.dedup();
+            lines
+        }
+
+        Step::Param(Param::Keep(re)) => keep(lines, &regex(re)?),
+        Step::Param(Param::Drop(re)) => drop(lines, &regex(re)?),
+        Step::Param(Param::Replace(re, with)) => {
+            let re = regex(re)?;
+            map(lines, |l| re.replace_all(&l, with.as_str()).into_owned())
+        }
+        Step::Param(Param::Version(m)) => version_match(lines, m)?,
+    })
+}
+
+fn body(ctx: &Context) -> Result<String, FetchError> {
+    if let Some(input) = ctx.input {
+        return input.body.clone().ok_or(FetchError::MissingInput("body"));
+    }
+
+    let body = http::get_cached("curl", ctx.upstream, &[])?;
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+fn regex(re: &str) -> Result<Regex, FetchError> {
+    Regex::new(re).map_err(|e| FetchError::InvalidRegex(re.to_string(), e))
+}
+
+fn map(lines: Vec<String>, f: impl Fn(String) -> String) -> Vec<String> {
+    lines.into_iter().map(f).collect()
+}
+
+fn filter(lines: Vec<String>, f: impl Fn(&String) -> bool) -> Vec<String> {
+    lines.into_iter().filter(f).collect()
+}
+
+fn keep(lines: Vec<String>, re: &Regex) -> Vec<String> {
+    filter(lines, |l| re.is_match(l))
+}
+
+fn drop(lines: Vec<String>, re: &Regex) -> Vec<String> {
+    filter(lines, |l| !re.is_match(l))
+}
+
+/// Trim the name, a "release" prefix, and a "v", "r", or "n" prefix, and replace underscores
+/// between numbers with dots, then apply any extra replacements
+fn trim(
+    lines: Vec<String>,
+    name: &str,
+    replacements: &[(String, String)],
+) -> Result<Vec<String>, FetchError> {
+    let replacements = replacements
+        .iter()
+        .map(|(re, with)| Ok((regex(re)?, with.as_str())))
+        .collect::<Result<Vec<_>, FetchError>>()?;
+
+    Ok(map(lines, |l| {
+        replacements
+            .iter()
+            .fold(ver::vtrim(&l, name), |l, (re, with)| re.replace_all(&l, *with).into_owned())
+    }))
+}
+
+/// Whether a version with at least 3 parts has an even minor version, like `vfem`
+fn is_even_minor(line: &str) -> bool {
+    if !THREE_PARTS.is_match(line) {
+        return false;
+    }
+
+    // like awk, a field without leading digits counts as 0
+    let minor = line.split('.').nth(1).unwrap_or_default();
+    let digits = minor
+        .find(|c: char| !c.is_ascii_digit())
+        .map_or(minor, |i| &minor[..i]);
+    digits.parse::<u64>().unwrap_or(0) % 2 == 0
+}
+
+/// Insert a dash before the first pre-release label of each kind that directly follows a digit
+fn predash(line: &str) -> String {
+    PRE_LABELS.iter().fold(line.to_string(), |l, label| {
+        let found = l
+            .match_indices(label)
+            .find(|(i, _)| l[..*i].ends_with(|c: char| c.is_ascii_digit()))
+            .map(|(i, _)| i);
+
+        match found {
+            Some(i) => format!("{}-{}", &l[..i], &l[i..]),
+            None => l,
+        }
+    })
+}
+
+fn version_match(lines: Vec<String>, m: &VersionMatch) -> Result<Vec<String>, FetchError> {
+    let parts = match m.parts {
+        0 => r"[0-9]+(\.[0-9]+)+".to_string(),
+        n @ 1..=5 => vec!["[0-9]+"; n.into()].join(r"\."),
+        n => {
+            return Err(FetchError::InvalidRegex(
+                format!("{n} parts"),
+                regex::Error::Syntax("up to 5 parts are supported".into()),
+            ));
+        }
+    };
+    let re = regex(&format!("{}{parts}{}", m.prefix, m.suffix))?;
+
+    Ok(if m.only {
+        lines
+            .iter()
+            .filter_map(|l| re.find(l).map(|m| m.as_str().to_string()))
+            .collect()
+    } else {
+        keep(lines, &re)
+    })
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[derive(Deserialize)]
+    struct Channel {
+        pipeline: Vec<Step>,
+    }
+
+    fn steps(toml: &str) -> Vec<Step> {
+        toml::from_str::<Channel>(toml).unwrap().pipeline
+    }
+
+    fn filter_lines(pipeline: &str, lines: &[&str]) -> Vec<String> {
+        let ctx = Context {
+            name: "foo",
+            upstream: "",
+            input: None,
+        };
+        steps(pipeline)
+            .iter()
+            .try_fold(
+                lines.iter().map(ToString::to_string).collect(),
+                |lines, step| apply(step, lines, &ctx),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn deserialize() {
+        assert_eq!(
+            steps(r#"pipeline = ["tags", { trim = [["_", "."]] }, { stable = "ga" }, "sort"]"#),
+            [
+                Step::Named(Named::Tags),
+                Step::Param(Param::Trim(vec![("_".into(), ".".into())])),
+                Step::Param(Param::Stable("ga".into())),
+                Step::Named(Named::Sort),
+            ]
+        );
+        assert_eq!(
+            steps(r"pipeline = [{ version = { parts = 3, only = true } }]"),
+            [Step::Param(Param::Version(VersionMatch {
+                parts: 3,
+                only: true,
+                ..Default::default()
+            }))]
+        );
+        assert!(toml::from_str::<Channel>(r#"pipeline = ["bogus"]"#).is_err());
+    }
+
+    #[test]
+    fn trim_and_sort() {
+        assert_eq!(
+            filter_lines(
+                r#"pipeline = ["lowercase", "trim", "no-leading-letter", "stable", "sort"]"#,
+                &[
+                    "FOO-1_2_3",
+                    "foo_1.10.0",
+                    "release-1.9",
+                    "v2.0.0-rc1",
+                    "latest",
+                    "v1.10.0"
+                ],
+            ),
+            ["1.2.3", "1.9", "1.10.0"]
+        );
+        assert_eq!(
+            filter_lines(
+                r#"pipeline = [{ trim = [["-", "."]] }]"#,
+                &["foo-1-2", "n3-4"]
+            ),
+            ["1.2", "3.4"]
+        );
+    }
+
+    #[test]
+    fn filters() {
+        let lines = [
+            "1.2.3",
+            "1.3.1",
+            "1.4.0-beta",
+            "2024-01-02",
+            "CVE-2024-1234",
+            "abc",
+        ];
+        assert_eq!(
+            filter_lines(r#"pipeline = ["even-minor"]"#, &lines),
+            ["1.2.3", "1.4.0-beta"]
+        );
+        assert_eq!(
+            filter_lines(r#"pipeline = ["no-dash", "no-letters"]"#, &lines),
+            ["1.2.3", "1.3.1"]
+        );
+        assert_eq!(
+            filter_lines(r#"pipeline = ["no-date", "no-cve", "has-digit"]"#, &lines),
+            ["1.2.3", "1.3.1", "1.4.0-beta"]
+        );
+        assert_eq!(
+            filter_lines(r"pipeline = [{ drop = '^1\.[23]' }]", &lines[..3]),
+            ["1.4.0-beta"]
+        );
+        assert_eq!(
+            filter_lines(r#"pipeline = ["predash"]"#, &["1.0rc1", "2.0beta2", "rc3"]),
+            ["1.0-rc1", "2.0-beta2", "rc3"]
+        );
+        assert_eq!(
+            filter_lines(
+                r#"pipeline = [{ version = { parts = 2, prefix = "foo-", only = true } }]"#,
+                &["see foo-1.2.tar.gz", "foo-3.tar.gz"]
+            ),
+            ["foo-1.2"]
+        );
+
+        let commit = "0123456789abcdef0123456789abcdef01234567";
+        assert_eq!(
+            filter_lines(r#"pipeline = ["commit"]"#, &[&format!("x {commit} y"), "z"]),
+            [commit]
+        );
+    }
+
+    #[test]
+    fn empty_output() {
+        let ctx = Context {
+            name: "foo",
+            upstream: "",
+            input: None,
+        };
+        assert!(matches!(
+            run(&steps(r#"pipeline = ["stable"]"#), &ctx),
+            Err(FetchError::EmptyStdout)
+        ));
+    }
+}
@@ -0,0 +1,75 @@
+// package/retry.rs
+//! Retrying transient fetch failures with exponential backoff
+//!
+//! The policy comes from `[retry]` in `config.toml`, and a channel's `retry` table overrides any
+//! of its fields. Each retry waits twice as long as the last, up to `max_backoff`, with the wait
+//! randomized between half and all of that to spread out retries to the same host.
+
+use std::hash::{Hash, Hasher};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::CONFIG;
+use crate::config::Retry;
+use crate::utils::float::defloat;
+
+/// # A channel's overrides for the retry policy
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryOverride {
+    pub attempts: Option<u32>,
+    pub backoff: Option<f64>,
+    pub max_backoff: Option<f64>,
+}
+
+impl Hash for RetryOverride {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.attempts.hash(state);
+        self.backoff.map(defloat).hash(state);
+        self.max_backoff.map(defloat).hash(state);
+    }
+}
+
+/// # The retry policy for a channel, given its overrides
+pub fn policy(over: Option<&RetryOverride>) -> Retry {
+    let global = CONFIG.get().expect("Config should be initialized").retry;
+    let Some(over) = over else {
+        return global;
+    };
+
+    Retry {
+        attempts: over.attempts.unwrap_or(global.attempts),
+        backoff: over.backoff.unwrap_or(global.backoff),
+        max_backoff: over.max_backoff.unwrap_or(global.max_backoff),
+    }
+}
+
+/// # How long to wait before the retry following a failed attempt, counting from 1
+pub fn delay(policy: &Retry, attempt: u32) -> Duration {
+    let exp = attempt.saturating_sub(1).min(32).cast_signed();
+    let ceiling = (policy.backoff * 2_f64.powi(exp)).min(policy.max_backoff);
+    Duration::try_from_secs_f64(ceiling * rand::random_range(0.5..=1.0)).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff() {
+        let policy = Retry {
+            attempts: 5,
+            backoff: 1.0,
+            max_backoff: 3.0,
+        };
+
+        for (attempt, ceiling) in [(1, 1.0), (2, 2.0), (3, 3.0), (4, 3.0), (40, 3.0)] {
+            let delay = delay(&policy, attempt).as_secs_f64();
+            assert!(
+                (ceiling / 2.0..=ceiling).contains(&delay),
+                "{delay} for attempt {attempt}"
+            );
+        }
+    }
+}
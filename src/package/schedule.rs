@@ -0,0 +1,171 @@
+// package/schedule.rs
+//! Adaptive scheduling based on how recently a channel's version changed
+//!
+//! Channels that changed recently are checked every run, while channels that haven't changed in
+//! a long time are checked with a probability inversely proportional to their age.
+
+use std::collections::HashMap;
+use std::process::Command;
+use std::sync::LazyLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use sha2::{Digest, Sha256};
+
+use super::Package;
+use crate::args::ARGS;
+use crate::config;
+use crate::{CONFIG, VAT_ROOT};
+
+const DAY: f64 = 86400.0;
+
+/// The seed for this run's rolls
+pub static SEED: LazyLock<u64> = LazyLock::new(|| ARGS.seed.unwrap_or_else(rand::random));
+
+/// Last change times of channel files, keyed by their path relative to the Vat root
+///
+/// Channel files are rewritten every run, so their mtimes don't say when they last changed.
+static HISTORY: LazyLock<HashMap<String, SystemTime>> = LazyLock::new(|| {
+    git_history().unwrap_or_else(|| {
+        warn!("Couldn't read git history for channels; every channel will be checked");
+        HashMap::new()
+    })
+});
+
+/// Read the last change time of every channel file from a single `git log`
+fn git_history() -> Option<HashMap<String, SystemTime>> {
+    let out = Command::new("git")
+        .args([
+            "log",
+            "--format=%x00%ct",
+            "--name-only",
+            "--",
+            ":(glob)p/**/channels/*",
+        ])
+        .current_dir(&*VAT_ROOT)
+        .output()
+        .ok()
+        .filter(|o| o.status.success())?;
+
+    let mut history = HashMap::new();
+    let mut time = UNIX_EPOCH;
+
+    for line in String::from_utf8_lossy(&out.stdout).lines() {
+        if let Some(ts) = line.strip_prefix('\0') {
+            time = UNIX_EPOCH + Duration::from_secs(ts.parse().ok()?);
+        } else if !line.is_empty() {
+            // git log lists the most recent commits first
+            history.entry(line.to_string()).or_insert(time);
+        }
+    }
+
+    debug!("Read git history for {} channel files", history.len());
+    Some(history)
+}
+
+/// When a channel's version last changed, if known
+pub fn last_changed(package: &Package, channel: &str) -> Option<SystemTime> {
+    let path = format!("p/{}/channels/{channel}", package.name);
+    HISTORY.get(&path).copied()
+}
+
+/// The age of a channel's version, if known
+pub fn age(package: &Package, channel: &str) -> Option<Duration> {
+    let changed = last_changed(package, channel)?;
+    Some(
+        SystemTime::now()
+            .duration_since(changed)
+            .unwrap_or_default(),
+    )
+}
+
+/// Compute the chance a channel should be checked from its age
+pub fn chance(package: &Package, channel: &str) -> f64 {
+    let schedule = &CONFIG.get().expect("Config should be initialized").schedule;
+    chance_for_age(age(package, channel), schedule)
+}
+
+/// The chance a channel of some age should be checked, or 1 if its age isn't known
+pub(super) fn chance_for_age(age: Option<Duration>, schedule: &config::Schedule) -> f64 {
+    let Some(age) = age else {
+        return 1.0;
+    };
+
+    #[allow(clippy::cast_precision_loss)]
+    let ratio = (schedule.scale as f64 * DAY) / age.as_secs_f64();
+    ratio.clamp(schedule.min_chance, 1.0)
+}
+
+/// Returns true with the given probability
+///
+/// The result depends only on the run's seed and `key`, so a run can be reproduced with `--seed`
+/// regardless of the order packages are fetched in, or the build of vat.
+pub fn roll(chance: f64, key: &str) -> bool {
+    roll_with(*SEED, chance, key)
+}
+
+fn roll_with(seed: u64, chance: f64, key: &str) -> bool {
+    if chance >= 1.0 {
+        return true;
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.update(seed.to_le_bytes());
+    hasher.update(key);
+    let hash = hasher.finalize();
+
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&hash[..8]);
+
+    // the top 53 bits, which an f64 holds exactly, as a fraction in [0, 1)
+    #[allow(clippy::cast_precision_loss)]
+    let x = (u64::from_le_bytes(bytes) >> 11) as f64 / (1_u64 << 53) as f64;
+    x < chance
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::{assert_eq, assert_ne};
+
+    const SCHEDULE: config::Schedule = config::Schedule {
+        scale: 120,
+        min_chance: 0.01,
+    };
+
+    /// The chance for a channel some days old, in percent
+    fn percent(days: Option<u64>) -> f64 {
+        let age = days.map(|n| Duration::from_hours(n * 24));
+        (chance_for_age(age, &SCHEDULE) * 100.0).round()
+    }
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn chance_by_age() {
+        assert_eq!(percent(None), 100.0);
+        assert_eq!(percent(Some(0)), 100.0);
+        assert_eq!(percent(Some(60)), 100.0);
+        assert_eq!(percent(Some(240)), 50.0);
+
+        // old enough that scale / age would go below the minimum
+        assert_eq!(percent(Some(120 * 1000)), 1.0);
+    }
+
+    #[test]
+    fn seeded_rolls() {
+        let keys = (0..1000).map(|i| format!("pkg{i}/release")).collect::<Vec<_>>();
+        let rolls = |seed| keys.iter().map(|k| roll_with(seed, 0.3, k)).collect::<Vec<_>>();
+
+        // the same seed always makes the same rolls, and a different one doesn't
+        assert_eq!(rolls(42), rolls(42));
+        assert_ne!(rolls(42), rolls(43));
+
+        // these are fixed, so they stay reproducible across builds
+        assert_eq!(rolls(42)[..8], [false, false, true, false, false, false, true, true]);
+
+        let hits = rolls(42).iter().filter(|r| **r).count();
+        assert!((250..350).contains(&hits), "{hits}");
+
+        assert!(keys.iter().all(|k| roll_with(42, 1.0, k)));
+        assert!(keys.iter().all(|k| !roll_with(42, 0.0, k)));
+    }
+}
@@ -0,0 +1,212 @@
+// package/stale.rs
+//! Report channels whose versions haven't changed in a long time
+//!
+//! These are worth confirming by hand, since they may belong to dead projects or have broken
+//! fetch commands that keep returning the same version.
+
+use std::fmt::Write;
+use std::fs;
+use std::time::{Duration, SystemTime};
+
+use color_eyre::Result;
+use serde::Serialize;
+
+use super::{Package, PackageChannel, schedule};
+use crate::CONFIG;
+
+#[derive(Debug, Serialize)]
+pub struct StaleChannel {
+    pub package: String,
+    pub channel: String,
+    pub version: String,
+    /// Last change as an RFC 3339 timestamp
+    pub last_changed: String,
+    pub age_days: u64,
+    /// The chance the channel is checked in a run
+    pub chance: f64,
+    /// Where the chance comes from: "channel", "package", or "schedule"
+    pub chance_source: &'static str,
+    pub upstream: String,
+}
+
+/// # Find channels unchanged for at least `threshold`, oldest first
+pub fn find_stale(packages: &[Package], threshold: Duration) -> Vec<StaleChannel> {
+    let now = SystemTime::now();
+    let mut stale = vec![];
+
+    for package in packages {
+        for channel in package.config.channels.iter().filter(|c| c.enabled) {
+            let path = package.get_package_path().join("channels").join(&channel.name);
+            let Ok(version) = fs::read_to_string(path) else {
+                continue;
+            };
+            let Some(changed) = schedule::last_changed(package, &channel.name) else {
+                continue;
+            };
+
+            stale.extend(stale_channel(package, channel, version.trim(), changed, now, threshold));
+        }
+    }
+
+    stale.sort_by(|a, b| b.age_days.cmp(&a.age_days).then_with(|| a.package.cmp(&b.package)));
+    stale
+}
+
+/// # Describe a channel if it was last changed at least `threshold` before `now`
+fn stale_channel(
+    package: &Package,
+    channel: &PackageChannel,
+    version: &str,
+    changed: SystemTime,
+    now: SystemTime,
+    threshold: Duration,
+) -> Option<StaleChannel> {
+    let age = now.duration_since(changed).unwrap_or_default();
+    if age < threshold {
+        return None;
+    }
+
+    let (chance, chance_source) = match (channel.chance, package.config.chance) {
+        (Some(c), _) => (c, "channel"),
+        (None, Some(c)) => (c, "package"),
+        (None, None) => {
+            let schedule = &CONFIG.get().expect("Config should be initialized").schedule;
+            (schedule::chance_for_age(Some(age), schedule), "schedule")
+        }
+    };
+
+    Some(StaleChannel {
+        package: package.name.clone(),
+        channel: channel.name.clone(),
+        version: version.to_string(),
+        last_changed: humantime::format_rfc3339_seconds(changed).to_string(),
+        age_days: age.as_secs() / 86400,
+        chance,
+        chance_source,
+        upstream: channel.upstream(package),
+    })
+}
+
+/// # Format stale channels as tab-separated plaintext
+pub fn format_plaintext(stale: &[StaleChannel]) -> String {
+    let mut s = String::new();
+    for c in stale {
+        let date = c.last_changed.split_once('T').map_or(c.last_changed.as_str(), |d| d.0);
+        let _ = writeln!(
+            s,
+            "{}\t{}\t{}\t{date} ({}d)\t{:.3}\t{}",
+            c.package, c.channel, c.version, c.age_days, c.chance, c.upstream
+        );
+    }
+    s
+}
+
+/// # Report stale channels as plaintext or JSON
+pub fn report(packages: &[Package], days: u64, json: bool) -> Result<String> {
+    let stale = find_stale(packages, Duration::from_secs(days * 86400));
+
+    if json {
+        Ok(serde_json::to_string_pretty(&stale)? + "\n")
+    } else {
+        Ok(format_plaintext(&stale))
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::time::UNIX_EPOCH;
+
+    const DAY: Duration = Duration::from_hours(24);
+
+    fn package() -> Package {
+        let mut package = Package {
+            name: "tree".into(),
+            config: toml::from_str(
+                r#"
+                upstream = "https://example.org/tree.git"
+
+                [[channels]]
+                name = "release"
+
+                [[channels]]
+                name = "unstable"
+                chance = 0.2
+                "#,
+            )
+            .unwrap(),
+        };
+        package.set_defaults();
+        package
+    }
+
+    /// Whether a channel last changed some time before now is stale, given `--days 180`
+    fn is_stale(age: Duration) -> bool {
+        let package = package();
+        let now = SystemTime::now();
+        stale_channel(&package, &package.config.channels[0], "1.0", now - age, now, DAY * 180)
+            .is_some()
+    }
+
+    #[test]
+    fn days_threshold() {
+        assert!(!is_stale(Duration::ZERO));
+        assert!(!is_stale((DAY * 180).saturating_sub(Duration::from_secs(1))));
+        assert!(is_stale(DAY * 180));
+        assert!(is_stale(DAY * 1000));
+
+        // a change in the future, like from clock skew, is as recent as it gets
+        let package = package();
+        let now = SystemTime::now();
+        let channel = &package.config.channels[0];
+        assert!(stale_channel(&package, channel, "1.0", now + DAY, now, DAY).is_none());
+    }
+
+    #[test]
+    fn json_shape() {
+        let package = package();
+        let changed = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let now = changed + DAY * 240;
+
+        let stale = package
+            .config
+            .channels
+            .iter()
+            .filter_map(|c| stale_channel(&package, c, "1.0", changed, now, DAY * 180))
+            .collect::<Vec<_>>();
+
+        // the schedule's chance is scale / age, 120 / 240 days
+        let expected = r#"[
+            {
+                "package": "tree",
+                "channel": "release",
+                "version": "1.0",
+                "last_changed": "2023-11-14T22:13:20Z",
+                "age_days": 240,
+                "chance": 0.5,
+                "chance_source": "schedule",
+                "upstream": "https://example.org/tree.git"
+            },
+            {
+                "package": "tree",
+                "channel": "unstable",
+                "version": "1.0",
+                "last_changed": "2023-11-14T22:13:20Z",
+                "age_days": 240,
+                "chance": 0.2,
+                "chance_source": "channel",
+                "upstream": "https://example.org/tree.git"
+            }
+        ]"#;
+        assert_eq!(
+            serde_json::to_value(&stale).unwrap(),
+            serde_json::from_str::<serde_json::Value>(expected).unwrap()
+        );
+        assert_eq!(
+            format_plaintext(&stale[..1]),
+            "tree\trelease\t1.0\t2023-11-14 (240d)\t0.500\thttps://example.org/tree.git\n"
+        );
+    }
+}
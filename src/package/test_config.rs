@@ -0,0 +1,319 @@
+// package/test_config.rs
+//! Offline tests for package configs
+//!
+//! A test gives sample responses from a package's upstream, like its tags or a directory listing,
+//! and the versions its channels should arrive at from them:
+//!
+//! ```toml
+//! [[tests]]
+//! tags = ["v1.0", "v1.1-rc1", "v1.1"]
+//! expected = { release = "1.1", unstable = "1.1-rc1" }
+//! ```
+//!
+//! Tests go in the package's `config`, or in their own files under `p/<pkg>/tests/`, where each
+//! `*.toml` file is a single test named after the file. A sample body can be read from a file with
+//! `body_file`, relative to the directory of the file defining the test.
+//!
+//! Pipelines read the samples directly. Bash fetches find them through `VAT_INPUT`, which `gr`,
+//! `githead`, and `cr` read from, and everything else is replayed from fixtures that don't exist,
+//! so a fetch that would reach the network fails instead.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use color_eyre::Result;
+use color_eyre::eyre::Context;
+use rayon::prelude::*;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use super::outcome::FetchError;
+use super::pipeline::{self, Input};
+use super::{Package, PackageChannel};
+use crate::VAT_CACHE;
+use crate::utils::fixtures::Mode;
+use crate::utils::str::basename;
+use crate::utils::ver::Version;
+
+/// # Sample responses from an upstream and the versions channels should arrive at
+#[derive(Debug, Default, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Test {
+    pub name: Option<String>,
+    pub tags: Vec<String>,
+    /// The commit HEAD points to
+    pub head: Option<String>,
+    pub body: Option<String>,
+    pub body_file: Option<PathBuf>,
+    /// Versions by channel
+    pub expected: BTreeMap<String, String>,
+}
+
+/// # The result of testing one channel
+struct Outcome {
+    label: String,
+    result: Result<String, String>,
+}
+
+/// # Run the tests for packages, returning the report and how many failed
+pub fn report(packages: &[Package]) -> Result<(String, usize)> {
+    let suites = packages
+        .iter()
+        .map(|p| Ok((p, tests(p)?)))
+        .collect::<Result<Vec<_>>>()?;
+
+    let outcomes = suites
+        .par_iter()
+        .map(|(package, tests)| {
+            tests
+                .iter()
+                .enumerate()
+                .flat_map(|(i, (test, dir))| run(package, test, dir, i))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    let mut s = String::new();
+    let mut passed = 0;
+    let mut failed = 0;
+    for ((package, tests), outcomes) in suites.iter().zip(outcomes) {
+        if tests.is_empty() {
+            // only worth pointing out for packages that were asked for
+            if packages.len() == 1 {
+                let _ = writeln!(s, "{}: no tests", package.name);
+            }
+            continue;
+        }
+
+        for outcome in outcomes {
+            match outcome.result {
+                Ok(version) => {
+                    passed += 1;
+                    let _ = writeln!(s, "ok\t{}\t{version}", outcome.label);
+                }
+                Err(e) => {
+                    failed += 1;
+                    let _ = writeln!(s, "FAIL\t{}\t{e}", outcome.label);
+                }
+            }
+        }
+    }
+
+    let _ = writeln!(s, "\n{passed} passed, {failed} failed");
+    Ok((s, failed))
+}
+
+/// The tests for a package, with the directory their files are relative to
+fn tests(package: &Package) -> Result<Vec<(Test, PathBuf)>> {
+    let dir = PathBuf::from(Package::dir(&package.name));
+    let mut tests = package
+        .config
+        .tests
+        .iter()
+        .map(|t| (t.clone(), dir.clone()))
+        .collect::<Vec<_>>();
+
+    let tests_dir = dir.join("tests");
+    let Ok(entries) = fs::read_dir(&tests_dir) else {
+        return Ok(tests);
+    };
+
+    let mut paths = entries
+        .filter_map(|e| Some(e.ok()?.path()))
+        .filter(|p| p.extension().is_some_and(|ext| ext == "toml"))
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    for path in paths {
+        let raw = fs::read_to_string(&path)
+            .wrap_err_with(|| format!("Couldn't read test at '{}'", path.display()))?;
+        let mut test = toml::from_str::<Test>(&raw)
+            .wrap_err_with(|| format!("Invalid test at '{}'", path.display()))?;
+        if test.name.is_none() {
+            test.name = path.file_stem().map(|s| s.to_string_lossy().into_owned());
+        }
+        tests.push((test, tests_dir.clone()));
+    }
+
+    Ok(tests)
+}
+
+/// Run a test against each channel it has an expected version for
+fn run(package: &Package, test: &Test, dir: &Path, i: usize) -> Vec<Outcome> {
+    let name = test.name.clone().unwrap_or_else(|| format!("#{}", i + 1));
+    let label = |channel: &str| format!("{}/{channel} ({name})", package.name);
+
+    let input = match input(test, dir) {
+        Ok(input) => input,
+        Err(e) => {
+            return test
+                .expected
+                .keys()
+                .map(|c| Outcome {
+                    label: label(c),
+                    result: Err(format!("{e:#}")),
+                })
+                .collect();
+        }
+    };
+
+    test.expected
+        .iter()
+        .map(|(channel, expected)| {
+            let result = package
+                .get_channel(channel)
+                .ok_or_else(|| "no such channel".to_string())
+                .and_then(|c| fetch(package, c, &input).map_err(|e| e.to_string()))
+                .and_then(|version| {
+                    if version == *expected {
+                        Ok(version)
+                    } else {
+                        Err(format!("got '{version}', expected '{expected}'"))
+                    }
+                });
+            Outcome {
+                label: label(channel),
+                result,
+            }
+        })
+        .collect()
+}
+
+fn input(test: &Test, dir: &Path) -> Result<Input> {
+    let body = match &test.body_file {
+        Some(file) => {
+            let path = dir.join(file);
+            let body = fs::read_to_string(&path)
+                .wrap_err_with(|| format!("Couldn't read body at '{}'", path.display()))?;
+            Some(body)
+        }
+        None => test.body.clone(),
+    };
+
+    Ok(Input {
+        tags: test.tags.clone(),
+        head: test.head.clone(),
+        body,
+    })
+}
+
+/// Fetch a channel's version from the samples, the way a run would
+fn fetch(package: &Package, channel: &PackageChannel, input: &Input) -> Result<String, FetchError> {
+    let output = if let Some(steps) = &channel.pipeline {
+        let upstream = channel.upstream(package);
+        let ctx = pipeline::Context {
+            name: basename(&package.name),
+            upstream: &upstream,
+            input: Some(input),
+        };
+        pipeline::run(steps, &ctx)?
+    } else {
+        let dir = write_input(input)?;
+        let path = dir.to_string_lossy();
+        let env = [("VAT_INPUT", &*path), (Mode::Replay.var(), &*path)];
+
+        // native backends would reach the network, so their bash counterparts run instead
+        let output = channel.bash_with(package, &channel.fetch, &env);
+        let _ = fs::remove_dir_all(&dir);
+        output?
+    };
+
+    let mut version = Version::new(output);
+    version.trim(package);
+    Ok(version.fmt)
+}
+
+/// Write samples where bash fetches can find them, with tags and HEAD as `vat ls-remote` lists them
+fn write_input(input: &Input) -> std::io::Result<PathBuf> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let dir = VAT_CACHE.join("tmp").join(format!("test-{}-{n}", std::process::id()));
+    fs::create_dir_all(&dir)?;
+
+    let mut refs = String::new();
+    if let Some(head) = &input.head {
+        let _ = writeln!(refs, "{head}\tHEAD");
+    }
+    for tag in &input.tags {
+        // the tags only need to look like they point somewhere
+        let oid = Sha256::digest(tag)
+            .iter()
+            .take(20)
+            .fold(String::new(), |mut s, b| {
+                let _ = write!(s, "{b:02x}");
+                s
+            });
+        let _ = writeln!(refs, "{oid}\trefs/tags/{tag}");
+    }
+    fs::write(dir.join("refs"), refs)?;
+
+    if let Some(body) = &input.body {
+        fs::write(dir.join("body"), body)?;
+    }
+
+    Ok(dir)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn package(config: &str) -> Package {
+        let mut package = Package {
+            // bash fetches run from the package's directory, so it has to exist
+            name: "tree".into(),
+            config: toml::from_str(config).unwrap(),
+        };
+        package.set_defaults();
+        package
+    }
+
+    // vat's subcommands aren't available to bash fetches here, since VAT_BIN is the test binary
+    #[test]
+    fn samples() {
+        let package = package(
+            r#"
+            upstream = "https://example.org/tree.git"
+
+            [[channels]]
+            name = "release"
+            fetch = "gr | vtrim | vfs | sort -V"
+
+            [[channels]]
+            name = "unstable"
+            pipeline = ["tags", "trim", "sort"]
+
+            [[channels]]
+            name = "listing"
+            fetch = "cr | grep -o 'tree-[0-9.]*[0-9]' | sort -V"
+            expected = ".*"
+
+            [[tests]]
+            tags = ["v1.0", "v1.1-rc1", "v1.1", "v1.2-rc1"]
+            body = "<a href='tree-2.0.tar.gz'>tree-2.0.tar.gz</a>"
+            expected = { release = "1.1", unstable = "1.2-rc1", listing = "2.0" }
+
+            [[tests]]
+            name = "wrong"
+            expected = { release = "1.0", missing = "1.0" }
+            "#,
+        );
+
+        let (report, failed) = report(&[package]).unwrap();
+        assert_eq!(failed, 2, "{report}");
+
+        let lines = report.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "ok\ttree/listing (#1)\t2.0");
+        assert_eq!(lines[1], "ok\ttree/release (#1)\t1.1");
+        assert_eq!(lines[2], "ok\ttree/unstable (#1)\t1.2-rc1");
+        assert_eq!(lines[3], "FAIL\ttree/missing (wrong)\tno such channel");
+        assert!(lines[4].starts_with("FAIL\ttree/release (wrong)\t"), "{report}");
+        assert_eq!(lines.last(), Some(&"3 passed, 2 failed"));
+    }
+}
@@ -0,0 +1,175 @@
+// utils/breaker.rs
+//! Circuit breaking for hosts that are down
+//!
+//! After `threshold` consecutive connection failures or timeouts, a host is tripped, and fetches
+//! from it are short-circuited for the rest of the run. Once every `interval`, a single fetch is
+//! let through as a probe, and if it reaches the host, the host is closed again.
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{LazyLock, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::CONFIG;
+
+/// The circuit breaker for fetches, as configured
+pub static BREAKER: LazyLock<Breaker> = LazyLock::new(|| {
+    let config = &CONFIG.get().expect("Config should be initialized").breaker;
+    Breaker::new(config.threshold, Duration::from_secs(config.interval))
+});
+
+#[derive(Debug, Default)]
+struct Host {
+    /// Consecutive connection failures
+    failures: usize,
+
+    /// When the host was tripped or last probed, if it's tripped
+    tripped: Option<Instant>,
+
+    /// Whether a probe is in flight
+    probing: bool,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    hosts: HashMap<String, Host>,
+
+    /// Every host tripped during the run, even if it was closed again
+    tripped: BTreeSet<String>,
+}
+
+/// # Health tracking for hosts
+pub struct Breaker {
+    threshold: usize,
+    interval: Duration,
+    state: Mutex<State>,
+}
+
+impl Breaker {
+    pub fn new(threshold: usize, interval: Duration) -> Self {
+        Self {
+            threshold,
+            interval,
+            state: Mutex::default(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// # Check whether a host is tripped, without taking its probe
+    pub fn is_open(&self, host: &str) -> bool {
+        let state = self.lock();
+        state.hosts.get(host).is_some_and(|h| {
+            h.tripped
+                .is_some_and(|at| h.probing || at.elapsed() < self.interval)
+        })
+    }
+
+    /// # Check whether a fetch from a host may go ahead
+    ///
+    /// For a tripped host, this lets through one probe per interval.
+    pub fn allow(&self, host: &str) -> bool {
+        let mut state = self.lock();
+        let Some(h) = state.hosts.get_mut(host) else {
+            return true;
+        };
+        let Some(at) = h.tripped else {
+            return true;
+        };
+
+        if h.probing || at.elapsed() < self.interval {
+            return false;
+        }
+
+        h.probing = true;
+        h.tripped = Some(Instant::now());
+        drop(state);
+
+        debug!("Probing {host}");
+        true
+    }
+
+    /// # Record whether a fetch from a host failed to connect
+    ///
+    /// Any other outcome, including errors from a host that answered, counts as the host being up.
+    pub fn record(&self, host: &str, connection_failed: bool) {
+        if self.threshold == 0 {
+            return;
+        }
+
+        let mut state = self.lock();
+        let h = state.hosts.entry(host.to_string()).or_default();
+        h.probing = false;
+
+        if !connection_failed {
+            if h.tripped.take().is_some() {
+                info!("Host {host} is reachable again");
+            }
+            h.failures = 0;
+            return;
+        }
+
+        h.failures += 1;
+        if h.failures < self.threshold {
+            return;
+        }
+
+        if h.tripped.is_none() {
+            warn!(
+                "Host {host} is unavailable after {} connection failures",
+                h.failures
+            );
+        }
+        h.tripped = Some(Instant::now());
+        state.tripped.insert(host.to_string());
+    }
+
+    /// # Every host tripped so far
+    pub fn tripped(&self) -> Vec<String> {
+        self.lock().tripped.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn trips_and_probes() {
+        let b = Breaker::new(2, Duration::from_millis(50));
+
+        b.record("a", true);
+        assert!(b.allow("a"));
+        b.record("a", true);
+        assert!(!b.allow("a"));
+        assert!(b.is_open("a"));
+        assert!(b.allow("b"));
+
+        // a single probe is let through after the interval, and a failure trips the host again
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(b.allow("a"));
+        assert!(!b.allow("a"));
+        b.record("a", true);
+        assert!(!b.allow("a"));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(b.allow("a"));
+        b.record("a", false);
+        assert!(b.allow("a"));
+        assert!(!b.is_open("a"));
+
+        assert_eq!(b.tripped(), ["a"]);
+    }
+
+    #[test]
+    fn disabled() {
+        let b = Breaker::new(0, Duration::from_mins(1));
+        for _ in 0..5 {
+            b.record("a", true);
+        }
+        assert!(b.allow("a"));
+        assert!(b.tripped().is_empty());
+    }
+}
@@ -0,0 +1,678 @@
+// utils/cache.rs
+//! Content-addressed cache for upstream responses
+//!
+//! Responses are stored once under `objects/`, named by the SHA-256 of their content, and each
+//! entry under `entries/` maps a kind of fetch and its source to an object, along with when it
+//! was created, how long it lives, and the validators the upstream sent. Expired entries are
+//! removed at the start of a run, along with the oldest entries if the cache is over its size
+//! limit, and objects no entry refers to.
+//!
+//! Files are written under a temporary name and renamed into place, so readers never see a
+//! partial response. Concurrent fetches of the same kind and source, even from different
+//! processes, are coalesced with a lock file per entry: the first caller fetches, and the rest
+//! wait for it and reuse its response.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write;
+use std::fs::{self, File, TryLockError};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use color_eyre::Result;
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{CONFIG, VAT_CACHE};
+
+/// The cache in `.vat-cache`, as configured
+pub static CACHE: LazyLock<Cache> = LazyLock::new(|| {
+    let config = CONFIG.get().expect("Config should be initialized");
+    Cache::new(
+        VAT_CACHE.clone(),
+        config.cache_timeout,
+        config.cache.ttl.clone(),
+        config.cache.max_size,
+    )
+});
+
+/// Files in the cache's root from before entries had metadata
+const LEGACY_PREFIXES: &[&str] = &["git-", "curl-", "ghapi-"];
+
+/// How long objects and temporary files are spared from cleaning after they're written, since
+/// another process may be about to write the entry referring to them
+const GRACE: Duration = Duration::from_mins(1);
+
+/// # A cached response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    /// The kind of fetch, like "git" or "curl"
+    pub kind: String,
+    /// What was fetched, usually a URL
+    pub source: String,
+    /// The SHA-256 of the response
+    pub object: String,
+    /// When the entry was created, in seconds since the Unix epoch
+    pub created: u64,
+    /// How long the entry lives, in seconds
+    pub ttl: u64,
+    pub size: u64,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Entry {
+    pub const fn is_expired(&self, now: u64) -> bool {
+        now.saturating_sub(self.created) >= self.ttl
+    }
+}
+
+/// # Which entries to purge
+#[derive(Debug, Default)]
+pub struct Filter<'a> {
+    pub expired: bool,
+    pub kind: Option<&'a str>,
+    pub sources: &'a [String],
+}
+
+impl Filter<'_> {
+    fn matches(&self, entry: &Entry, now: u64) -> bool {
+        (!self.expired || entry.is_expired(now))
+            && self.kind.is_none_or(|k| k == entry.kind)
+            && (self.sources.is_empty() || self.sources.contains(&entry.source))
+    }
+}
+
+/// # A cache directory and its limits
+pub struct Cache {
+    root: PathBuf,
+    default_ttl: u64,
+    ttls: HashMap<String, u64>,
+    max_size: u64,
+}
+
+impl Cache {
+    pub const fn new(
+        root: PathBuf,
+        default_ttl: u64,
+        ttls: HashMap<String, u64>,
+        max_size: u64,
+    ) -> Self {
+        Self {
+            root,
+            default_ttl,
+            ttls,
+            max_size,
+        }
+    }
+
+    fn objects(&self) -> PathBuf {
+        self.root.join("objects")
+    }
+
+    fn entries_dir(&self) -> PathBuf {
+        self.root.join("entries")
+    }
+
+    fn locks(&self) -> PathBuf {
+        self.root.join("locks")
+    }
+
+    fn entry_path(&self, kind: &str, source: &str) -> PathBuf {
+        self.entries_dir().join(key(kind, source) + ".json")
+    }
+
+    fn read_entry(path: &Path) -> Option<Entry> {
+        let json = fs::read_to_string(path).ok()?;
+        serde_json::from_str(&json).ok()
+    }
+
+    /// # The path to a fresh, non-empty response, if one is cached
+    pub fn get(&self, kind: &str, source: &str) -> Option<PathBuf> {
+        let entry = Self::read_entry(&self.entry_path(kind, source))?;
+        if entry.size == 0 || entry.is_expired(now()) {
+            return None;
+        }
+
+        let object = self.objects().join(&entry.object);
+        object.exists().then_some(object)
+    }
+
+    /// # Store a response, returning the path to its object
+    ///
+    /// The `ETag` and `Last-Modified` validators are taken from `headers`, a dump of the response
+    /// headers like `curl -D` writes, if given.
+    pub fn put(
+        &self,
+        kind: &str,
+        source: &str,
+        data: &[u8],
+        headers: Option<&str>,
+    ) -> io::Result<PathBuf> {
+        if kind.is_empty() || !kind.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid cache kind '{kind}'"),
+            ));
+        }
+
+        let hash = hex(&Sha256::digest(data));
+        let object = self.objects().join(&hash);
+        // an existing object is touched, so it isn't collected before the entry is written
+        match File::options().append(true).open(&object) {
+            Ok(file) => file.set_modified(SystemTime::now())?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => write_atomic(&object, data)?,
+            Err(e) => return Err(e),
+        }
+
+        let (etag, last_modified) = headers.map(validators).unwrap_or_default();
+        let entry = Entry {
+            kind: kind.to_string(),
+            source: source.to_string(),
+            object: hash,
+            created: now(),
+            ttl: self.ttls.get(kind).copied().unwrap_or(self.default_ttl),
+            size: data.len() as u64,
+            etag,
+            last_modified,
+        };
+
+        write_atomic(&self.entry_path(kind, source), &serde_json::to_vec_pretty(&entry)?)?;
+
+        Ok(object)
+    }
+
+    /// # Hold the lock on a kind and source until the returned file is dropped
+    ///
+    /// This also returns whether another caller held the lock first.
+    fn lock(&self, kind: &str, source: &str) -> io::Result<(File, bool)> {
+        fs::create_dir_all(self.locks())?;
+        let file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.locks().join(key(kind, source)))?;
+
+        match file.try_lock() {
+            Ok(()) => Ok((file, false)),
+            Err(TryLockError::WouldBlock) => {
+                trace!("Waiting on another fetch of {source} ({kind})");
+                file.lock()?;
+                Ok((file, true))
+            }
+            Err(TryLockError::Error(e)) => Err(e),
+        }
+    }
+
+    /// # The path to a cached response, fetching and caching it if there isn't a fresh one
+    ///
+    /// Only one caller fetches a kind and source at a time, and callers that waited on it reuse
+    /// its response. With `refresh`, only a response fetched while waiting is reused. `fetch`
+    /// returns the response and a dump of its headers, if any.
+    pub fn fetch(
+        &self,
+        kind: &str,
+        source: &str,
+        refresh: bool,
+        fetch: impl FnOnce() -> Result<(Vec<u8>, Option<String>)>,
+    ) -> Result<PathBuf> {
+        if !refresh && let Some(path) = self.get(kind, source) {
+            return Ok(path);
+        }
+
+        // whoever held the lock was fetching the same thing, so its response is as fresh as ours
+        let (_lock, waited) = self.lock(kind, source)?;
+        if (waited || !refresh) && let Some(path) = self.get(kind, source) {
+            return Ok(path);
+        }
+
+        let (data, headers) = fetch()?;
+        Ok(self.put(kind, source, &data, headers.as_deref())?)
+    }
+
+    /// # Like [`Cache::fetch`], for fetches with their own error type, returning the response
+    ///
+    /// A failed fetch returns its own error, while if the cache itself fails, the fetch is made
+    /// without it.
+    pub fn fetch_with<E>(
+        &self,
+        kind: &str,
+        source: &str,
+        refresh: bool,
+        fetch: impl Fn() -> Result<(Vec<u8>, Option<String>), E>,
+    ) -> Result<Vec<u8>, E> {
+        let mut failed = None;
+        let cached = self.fetch(kind, source, refresh, || {
+            fetch().map_err(|e| {
+                failed = Some(e);
+                eyre!("fetch failed")
+            })
+        });
+        if let Some(e) = failed {
+            return Err(e);
+        }
+
+        match cached.and_then(|path| Ok(fs::read(path)?)) {
+            Ok(data) => Ok(data),
+            Err(e) => {
+                warn!("Couldn't cache {source} ({kind}): {e:#}");
+                fetch().map(|(data, _)| data)
+            }
+        }
+    }
+
+    /// # Every entry with its path, oldest first
+    pub fn entries(&self) -> Vec<(PathBuf, Entry)> {
+        let Ok(dir) = fs::read_dir(self.entries_dir()) else {
+            return vec![];
+        };
+
+        let mut entries = dir
+            .filter_map(|e| {
+                let path = e.ok()?.path();
+                let entry = Self::read_entry(&path)?;
+                Some((path, entry))
+            })
+            .collect::<Vec<_>>();
+        entries.sort_by(|(_, a), (_, b)| a.created.cmp(&b.created).then(a.source.cmp(&b.source)));
+        entries
+    }
+
+    /// # Remove entries matching a filter, returning how many and their total size
+    pub fn purge(&self, filter: &Filter) -> (usize, u64) {
+        let now = now();
+        let mut removed = (0, 0);
+
+        for (path, entry) in self.entries() {
+            if filter.matches(&entry, now) && fs::remove_file(path).is_ok() {
+                removed.0 += 1;
+                removed.1 += entry.size;
+            }
+        }
+
+        self.collect_garbage();
+        removed
+    }
+
+    /// # Remove expired entries, then the oldest entries until the cache fits its size limit
+    pub fn clean(&self) -> io::Result<()> {
+        fs::create_dir_all(&self.root)?;
+        self.remove_stale();
+
+        let (n, size) = self.purge(&Filter {
+            expired: true,
+            ..Filter::default()
+        });
+        if n > 0 {
+            debug!("Removed {n} expired cache entries ({})", human_size(size));
+        }
+
+        if self.max_size > 0 {
+            let entries = self.entries();
+            let mut refs = HashMap::<&str, usize>::new();
+            for (_, entry) in &entries {
+                *refs.entry(&entry.object).or_default() += 1;
+            }
+
+            // objects are shared, so only removing an object's last entry frees its size
+            let mut total = self.objects_size();
+            for (path, entry) in &entries {
+                if total <= self.max_size {
+                    break;
+                }
+                debug!("Evicting {} ({}) to fit the cache's size limit", entry.source, entry.kind);
+                if fs::remove_file(path).is_ok()
+                    && let Some(n) = refs.get_mut(entry.object.as_str())
+                {
+                    *n -= 1;
+                    if *n == 0 {
+                        total = total.saturating_sub(entry.size);
+                    }
+                }
+            }
+            self.collect_garbage();
+        }
+
+        Ok(())
+    }
+
+    /// Remove objects no entry refers to, unless they were just written
+    fn collect_garbage(&self) {
+        let referenced = self
+            .entries()
+            .into_iter()
+            .map(|(_, e)| e.object)
+            .collect::<HashSet<_>>();
+
+        let Ok(dir) = fs::read_dir(self.objects()) else {
+            return;
+        };
+        for object in dir.filter_map(Result::ok) {
+            let name = object.file_name();
+            let name = name.to_string_lossy();
+            // objects still being written have temporary names
+            if !name.starts_with('.') && !referenced.contains(name.as_ref()) && !is_recent(&object)
+            {
+                let _ = fs::remove_file(object.path());
+            }
+        }
+    }
+
+    /// Remove files left behind by older versions of vat, or by runs that were killed
+    ///
+    /// Temporary files written in the last minute may belong to a running fetch, so they're kept.
+    /// Lock files are left alone, since another process may hold or wait on one, and they're
+    /// empty anyway.
+    fn remove_stale(&self) {
+        let dirs = [
+            (self.root.clone(), LEGACY_PREFIXES),
+            (self.objects(), &[".tmp-"][..]),
+            (self.entries_dir(), &[".tmp-"][..]),
+        ];
+        for (dir, prefixes) in dirs {
+            let Ok(dir) = fs::read_dir(dir) else {
+                continue;
+            };
+            for file in dir.filter_map(Result::ok) {
+                let name = file.file_name();
+                let name = name.to_string_lossy();
+                if prefixes.iter().any(|p| name.starts_with(p)) && !is_recent(&file) {
+                    let _ = fs::remove_file(file.path());
+                }
+            }
+        }
+    }
+
+    fn objects_size(&self) -> u64 {
+        fs::read_dir(self.objects()).map_or(0, |dir| {
+            dir.filter_map(|o| o.ok()?.metadata().ok())
+                .map(|m| m.len())
+                .sum()
+        })
+    }
+
+    /// # List entries, oldest first
+    pub fn ls(&self, json: bool) -> Result<String> {
+        let entries = self.entries().into_iter().map(|(_, e)| e).collect::<Vec<_>>();
+        if json {
+            return Ok(serde_json::to_string_pretty(&entries)? + "\n");
+        }
+
+        let now = now();
+        let mut s = String::new();
+        for e in &entries {
+            let expires = if e.is_expired(now) {
+                "expired".to_string()
+            } else {
+                let left = Duration::from_secs(e.created + e.ttl - now);
+                format!("expires in {}", humantime::format_duration(left))
+            };
+            let _ = writeln!(
+                s,
+                "{}\t{}\t{}\t{expires}\t{}",
+                e.kind,
+                human_size(e.size),
+                timestamp(e.created),
+                e.source
+            );
+        }
+        Ok(s)
+    }
+
+    /// # Summarize the cache's entries and size
+    pub fn stat(&self) -> String {
+        let now = now();
+        let entries = self.entries();
+        let expired = entries.iter().filter(|(_, e)| e.is_expired(now)).count();
+
+        let mut kinds = BTreeMap::<&str, (usize, u64)>::new();
+        for (_, e) in &entries {
+            let kind = kinds.entry(&e.kind).or_default();
+            kind.0 += 1;
+            kind.1 += e.size;
+        }
+
+        let limit = if self.max_size > 0 {
+            format!(" of {}", human_size(self.max_size))
+        } else {
+            String::new()
+        };
+
+        let mut s = String::new();
+        let _ = writeln!(s, "entries: {} ({expired} expired)", entries.len());
+        let _ = writeln!(s, "size: {}{limit}", human_size(self.objects_size()));
+        for (kind, (n, size)) in kinds {
+            let ttl = self.ttls.get(kind).copied().unwrap_or(self.default_ttl);
+            let _ = writeln!(
+                s,
+                "{kind}: {n} entries, {}, ttl {}",
+                human_size(size),
+                humantime::format_duration(Duration::from_secs(ttl))
+            );
+        }
+        s
+    }
+}
+
+/// Write a file under a temporary name, then rename it into place
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    fs::create_dir_all(dir)?;
+
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let tmp = dir.join(format!(".tmp-{}-{n}", std::process::id()));
+    fs::write(&tmp, data)
+        .and_then(|()| fs::rename(&tmp, path))
+        .inspect_err(|_| {
+            let _ = fs::remove_file(&tmp);
+        })
+}
+
+/// The name of an entry's metadata and lock files
+/// Whether a file was modified within [`GRACE`]
+fn is_recent(file: &fs::DirEntry) -> bool {
+    file.metadata()
+        .and_then(|m| m.modified())
+        .is_ok_and(|t| t.elapsed().is_ok_and(|age| age < GRACE))
+}
+
+fn key(kind: &str, source: &str) -> String {
+    hex(&Sha256::digest(format!("{kind}\n{source}")))
+}
+
+/// The validators in the last response of a header dump, which follows any redirects
+fn validators(headers: &str) -> (Option<String>, Option<String>) {
+    let headers = headers.replace("\r\n", "\n");
+    let last = headers
+        .split("\n\n")
+        .filter(|block| block.trim_start().starts_with("HTTP/"))
+        .last()
+        .unwrap_or_default();
+
+    let header = |name: &str| {
+        last.lines().find_map(|line| {
+            let (k, v) = line.split_once(':')?;
+            k.trim().eq_ignore_ascii_case(name).then(|| v.trim().to_string())
+        })
+    };
+    (header("etag"), header("last-modified"))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn timestamp(secs: u64) -> String {
+    humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(secs)).to_string()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{b:02x}");
+        s
+    })
+}
+
+#[allow(clippy::cast_precision_loss)]
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn cache(name: &str, max_size: u64) -> Cache {
+        let root = std::env::temp_dir().join(format!("vat-cache-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        Cache::new(root, 3600, HashMap::from([("git".into(), 0)]), max_size)
+    }
+
+    /// Make every object old enough to be collected
+    fn backdate(cache: &Cache) {
+        for object in fs::read_dir(cache.objects()).unwrap() {
+            let file = File::options().append(true).open(object.unwrap().path()).unwrap();
+            file.set_modified(SystemTime::now() - GRACE * 2).unwrap();
+        }
+    }
+
+    #[test]
+    fn entries_share_objects() {
+        let cache = cache("share", 0);
+        let headers = "HTTP/1.1 301 Moved\r\nETag: \"old\"\r\n\r\nHTTP/1.1 200 OK\r\nETag: \"new\"\r\nLast-Modified: Tue, 01 Jul 2025 00:00:00 GMT\r\n\r\n";
+
+        let a = cache.put("curl", "https://a/", b"same", Some(headers)).unwrap();
+        let b = cache.put("curl", "https://b/", b"same", None).unwrap();
+        assert_eq!(a, b);
+        assert_eq!(cache.get("curl", "https://a/"), Some(a));
+        assert_eq!(cache.get("curl", "https://c/"), None);
+
+        let entries = cache.entries();
+        let (_, first) = entries.iter().find(|(_, e)| e.source == "https://a/").unwrap();
+        assert_eq!(first.etag.as_deref(), Some("\"new\""));
+        assert_eq!(first.last_modified.as_deref(), Some("Tue, 01 Jul 2025 00:00:00 GMT"));
+
+        // objects stay as long as any entry refers to them
+        let sources = ["https://a/".to_string()];
+        let filter = Filter {
+            sources: &sources,
+            ..Filter::default()
+        };
+        assert_eq!(cache.purge(&filter), (1, 4));
+        assert!(cache.get("curl", "https://b/").is_some());
+
+        let _ = fs::remove_dir_all(&cache.root);
+    }
+
+    #[test]
+    fn coalesces_fetches() {
+        let cache = cache("flight", 0);
+        let fetched = AtomicUsize::new(0);
+
+        let fetch = || {
+            fetched.fetch_add(1, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(100));
+            Ok((b"refs".to_vec(), None))
+        };
+
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| cache.fetch("curl", "https://a/", false, fetch).unwrap());
+            }
+        });
+        assert_eq!(fetched.load(Ordering::SeqCst), 1);
+
+        // a refresh fetches again, even though there's a fresh response
+        cache.fetch("curl", "https://a/", true, fetch).unwrap();
+        assert_eq!(fetched.load(Ordering::SeqCst), 2);
+
+        let _ = fs::remove_dir_all(&cache.root);
+    }
+
+    #[test]
+    fn fetch_with_own_errors() {
+        let cache = cache("with", 0);
+
+        let failed = cache.fetch_with("curl", "https://a/", false, || Err::<_, u16>(404));
+        assert_eq!(failed, Err(404));
+        assert_eq!(cache.get("curl", "https://a/"), None);
+
+        let ok = cache.fetch_with("curl", "https://a/", false, || Ok::<_, u16>((b"body".to_vec(), None)));
+        assert_eq!(ok.as_deref(), Ok(&b"body"[..]));
+
+        // lock files outlive cleaning, since other processes may be holding them
+        cache.clean().unwrap();
+        assert!(cache.locks().join(key("curl", "https://a/")).exists());
+
+        let _ = fs::remove_dir_all(&cache.root);
+    }
+
+    #[test]
+    fn expiry_and_eviction() {
+        let cache = cache("evict", 8);
+
+        // git entries expire immediately
+        cache.put("git", "https://git/", b"refs", None).unwrap();
+        assert_eq!(cache.get("git", "https://git/"), None);
+
+        cache.put("curl", "https://old/", b"12345", None).unwrap();
+        cache.put("curl", "https://new/", b"67890", None).unwrap();
+        cache.put("curl", "https://empty/", b"", None).unwrap();
+        assert_eq!(cache.get("curl", "https://empty/"), None);
+
+        // entries created in the same second are evicted in order of their sources
+        backdate(&cache);
+        cache.clean().unwrap();
+        let sources = cache.entries().into_iter().map(|(_, e)| e.source).collect::<Vec<_>>();
+        assert_eq!(sources, ["https://old/"]);
+        assert_eq!(cache.objects_size(), 5);
+
+        let _ = fs::remove_dir_all(&cache.root);
+    }
+
+    #[test]
+    fn evicts_shared_objects() {
+        let cache = cache("evict-shared", 8);
+
+        cache.put("curl", "https://a/", b"12345", None).unwrap();
+        cache.put("curl", "https://b/", b"12345", None).unwrap();
+        cache.put("curl", "https://c/", b"67890", None).unwrap();
+
+        // objects written moments ago may be waiting on their entries
+        assert_eq!(cache.purge(&Filter::default()), (3, 15));
+        assert_eq!(cache.objects_size(), 10);
+
+        cache.put("curl", "https://a/", b"12345", None).unwrap();
+        cache.put("curl", "https://b/", b"12345", None).unwrap();
+        cache.put("curl", "https://c/", b"67890", None).unwrap();
+        backdate(&cache);
+
+        // removing a's entry alone doesn't free its object, which b still refers to
+        cache.clean().unwrap();
+        let sources = cache.entries().into_iter().map(|(_, e)| e.source).collect::<Vec<_>>();
+        assert_eq!(sources, ["https://c/"]);
+        assert_eq!(cache.objects_size(), 5);
+
+        let _ = fs::remove_dir_all(&cache.root);
+    }
+}
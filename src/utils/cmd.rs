@@ -1,80 +1,271 @@
-// utils/sh.rs
+// utils/cmd.rs
 
 use std::collections::HashMap;
 use std::io::{self, Read};
-use std::process::{Command, Stdio};
-use std::time::Duration;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+use std::sync::{LazyLock, mpsc};
+use std::time::{Duration, Instant};
 use std::thread;
 
-use color_eyre::Result;
-use color_eyre::eyre::Context;
+use regex::Regex;
 use thiserror::Error;
-use wait_timeout::ChildExt;
 
 use crate::CONFIG;
+use crate::utils::http::HttpError;
+use crate::utils::sandbox::Sandbox;
+
+/// Built-in patterns for classifying stderr, as (pattern, error)
+///
+/// For "http", the status code is taken from the `code` capture group.
+const STDERR_PATTERNS: &[(&str, &str)] = &[
+    (r"(?i)rate limit", "rate-limited"),
+    // curl and git both phrase http errors like this
+    (r"The requested URL returned error: (?P<code>[0-9]{3})", "http"),
+    (r"vat: http status (?P<code>[0-9]{3})", "http"),
+    // curl (6), git, and vat
+    (r"Could not resolve host", "dns"),
+    (r"failed to lookup address information", "dns"),
+    (r"vat: host not found", "dns"),
+    // curl (35) and (60), git with openssl or gnutls, and vat
+    (r"curl: \((35|60)\)", "tls"),
+    (r"SSL certificate problem|gnutls_handshake\(\) failed|OpenSSL SSL_", "tls"),
+    (r"vat: tls error", "tls"),
+    // newer curl reports refused connections as (7) "Couldn't connect to server"
+    (r"Connection refused|curl: \(7\)", "connection-refused"),
+    (r"Connection reset by peer|curl: \(56\)", "connection-reset"),
+    (r"curl: \(28\)|Operation timed out|Connection timed out|vat: timeout", "timeout"),
+    // git prompts for credentials when a GitHub repository doesn't exist
+    (r"repository '.*' not found|could not read Username", "not-found"),
+    // jq
+    (r"jq: error \(at ", "unexpected-response"),
+    (r"parse error: .* at line [0-9]+, column [0-9]+", "unexpected-response"),
+];
+
+static CLASSIFIER: LazyLock<Vec<(Regex, String)>> = LazyLock::new(|| {
+    let config = CONFIG.get().expect("Config should be initialized");
+
+    // patterns from the config take precedence
+    config
+        .stderr_patterns
+        .iter()
+        .map(|p| (p.pattern.as_str(), p.error.as_str()))
+        .chain(STDERR_PATTERNS.iter().copied())
+        .filter_map(|(pattern, error)| {
+            if UpstreamError::from_kind(error, Some(0)).is_none() {
+                warn!("Ignoring stderr pattern '{pattern}' with unknown error '{error}'");
+                return None;
+            }
+
+            match Regex::new(pattern) {
+                Ok(re) => Some((re, error.to_string())),
+                Err(e) => {
+                    warn!("Ignoring invalid stderr pattern '{pattern}': {e}");
+                    None
+                }
+            }
+        })
+        .collect()
+});
+
+/// # A recognized upstream failure
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UpstreamError {
+    #[error("http status {0}")]
+    Http(u16),
+
+    #[error("rate limited")]
+    RateLimited,
+
+    #[error("dns failure")]
+    Dns,
+
+    #[error("tls failure")]
+    Tls,
+
+    #[error("connection refused")]
+    ConnectionRefused,
+
+    #[error("connection reset")]
+    ConnectionReset,
+
+    #[error("connection timed out")]
+    Timeout,
+
+    #[error("repository not found")]
+    NotFound,
+
+    #[error("unexpected response")]
+    UnexpectedResponse,
+}
+
+impl UpstreamError {
+    /// Construct an error from its kind as written in the config
+    pub fn from_kind(kind: &str, code: Option<u16>) -> Option<Self> {
+        Some(match kind {
+            "http" => match code? {
+                429 => Self::RateLimited,
+                code => Self::Http(code),
+            },
+            "rate-limited" => Self::RateLimited,
+            "dns" => Self::Dns,
+            "tls" => Self::Tls,
+            "connection-refused" => Self::ConnectionRefused,
+            "connection-reset" => Self::ConnectionReset,
+            "timeout" => Self::Timeout,
+            "not-found" => Self::NotFound,
+            "unexpected-response" => Self::UnexpectedResponse,
+            _ => return None,
+        })
+    }
+
+    /// A short, stable name for the error, for use in run statistics
+    pub fn kind(self) -> String {
+        match self {
+            Self::Http(code) => format!("http-{code}"),
+            Self::RateLimited => "rate-limited".into(),
+            Self::Dns => "dns".into(),
+            Self::Tls => "tls".into(),
+            Self::ConnectionRefused => "connection-refused".into(),
+            Self::ConnectionReset => "connection-reset".into(),
+            Self::Timeout => "timeout".into(),
+            Self::NotFound => "not-found".into(),
+            Self::UnexpectedResponse => "unexpected-response".into(),
+        }
+    }
+
+    /// Classify an error from a native HTTP request
+    pub fn from_http(e: &HttpError) -> Option<Self> {
+        match e {
+            HttpError::Status(code) => Self::from_kind("http", Some(*code)),
+            HttpError::HostNotFound => Some(Self::Dns),
+            HttpError::Timeout => Some(Self::Timeout),
+            HttpError::Tls(_) => Some(Self::Tls),
+            HttpError::Io(e) => match e.kind() {
+                io::ErrorKind::ConnectionRefused => Some(Self::ConnectionRefused),
+                io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted => {
+                    Some(Self::ConnectionReset)
+                }
+                io::ErrorKind::TimedOut => Some(Self::Timeout),
+                _ => classify(&e.to_string()),
+            },
+            HttpError::Other(msg) => classify(msg),
+            HttpError::NoFixture(_) => None,
+        }
+    }
+}
+
+/// # Classify stderr from a fetch
+pub fn classify(stderr: &str) -> Option<UpstreamError> {
+    CLASSIFIER.iter().find_map(|(re, error)| {
+        let caps = re.captures(stderr)?;
+        let code = caps.name("code").and_then(|c| c.as_str().parse().ok());
+        UpstreamError::from_kind(error, code)
+    })
+}
 
 #[derive(Error, Debug)]
-enum CmdError {
-    #[error("output in stderr")]
-    OutputInStderr,
+pub enum CmdError {
+    #[error(transparent)]
+    Upstream(UpstreamError),
 
-    #[error("nonzero status")]
-    NonzeroStatus,
+    #[error("output in stderr: {}", .0.trim())]
+    StderrOutput(String),
 
-    #[error("empty stdout")]
+    #[error("exited with nonzero status: {0}")]
+    NonzeroStatus(i32),
+
+    #[error("no output in stdout")]
     EmptyStdout,
 
     #[error("timeout")]
     Timeout,
 
+    #[error("output pipes held open after exiting")]
+    PipesHeldOpen,
+
     #[error("io error")]
     Io(#[from] io::Error),
 }
 
+/// How long a timed out command has to exit after SIGTERM before it's killed
+const KILL_GRACE: Duration = Duration::from_secs(2);
+
+/// How long to wait for stdout and stderr to close once a command has exited
+const PIPE_GRACE: Duration = Duration::from_secs(2);
+
+/// The longest to sleep between checks on whether a command has exited
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
 /// # Lowish level function to execute a command and return stdout
+///
+/// With a sandbox, the command only sees `env` and the variables the sandbox passes through.
+pub fn cmd(
+    cmd: &[&str],
+    env: HashMap<&str, &str>,
+    cwd: &str,
+    sandbox: Option<&'static Sandbox>,
+) -> Result<String, CmdError> {
+    let timeout = CONFIG.get().expect("Config should be initialized").fetch_timeout;
+    cmd_with_timeout(cmd, env, cwd, sandbox, Duration::from_secs(timeout))
+}
+
+/// # Execute a command with a timeout and return stdout
+///
+/// The command runs in its own process group, so the whole pipeline is terminated on timeout,
+/// not just the shell running it.
 #[allow(clippy::similar_names, clippy::unwrap_used)]
-pub fn cmd(cmd: &[&str], env: HashMap<&str, &str>, cwd: &str) -> Result<String> {
+fn cmd_with_timeout(
+    cmd: &[&str],
+    env: HashMap<&str, &str>,
+    cwd: &str,
+    sandbox: Option<&'static Sandbox>,
+    timeout: Duration,
+) -> Result<String, CmdError> {
     trace!("Evaluating command: {}", cmd.join(" "));
 
     let (arg0, args) = cmd.split_first().expect("command should not be empty");
-    let mut child = Command::new(arg0)
+    let mut command = Command::new(arg0);
+    match sandbox {
+        // the sandbox creates the process group itself, since it forks before exec
+        Some(sandbox) => sandbox.apply(&mut command),
+        None => {
+            command.process_group(0);
+        }
+    }
+
+    let mut child = command
         .args(args)
         .envs(env)
         .current_dir(cwd)
         .stdin(Stdio::null())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
-        .spawn()
-        .wrap_err("Failed to spawn command")?;
+        .spawn()?;
 
-    let timeout = CONFIG.get().expect("Config should be initialized").fetch_timeout;
-    trace!("Spawned command with a timeout of {timeout} seconds");
+    trace!("Spawned command with a timeout of {timeout:?}");
 
-    let mut stdout = child.stdout.take().unwrap();
-    let mut stderr = child.stderr.take().unwrap();
+    let out_rx = read_pipe(child.stdout.take().unwrap());
+    let err_rx = read_pipe(child.stderr.take().unwrap());
 
-    let out_thread = thread::spawn(move || {
-        let mut buf = Vec::new();
-        stdout.read_to_end(&mut buf).unwrap();
-        buf
-    });
+    if !wait_exited(&child, timeout)? {
+        terminate(&mut child)?;
+        return Err(CmdError::Timeout);
+    }
 
-    let err_thread = thread::spawn(move || {
-        let mut buf = Vec::new();
-        stderr.read_to_end(&mut buf).unwrap();
-        buf
-    });
+    // anything left in the group, like a backgrounded process, could hold the pipes open
+    signal_group(&child, libc::SIGKILL);
+    let status = child.wait()?;
 
-    let timeout = Duration::from_secs(timeout);
-    let Some(status) = child.wait_timeout(timeout)? else {
-        child.kill().expect("Could not kill child");
-        child.wait().expect("Failed to wait on child");
-        return Err(CmdError::Timeout).wrap_err("Timed out");
+    // if something outside the group still holds the pipes, give up on it rather than hang
+    let (Ok(out_buf), Ok(err_buf)) = (
+        out_rx.recv_timeout(PIPE_GRACE),
+        err_rx.recv_timeout(PIPE_GRACE),
+    ) else {
+        warn!("Output pipes were held open after the command exited");
+        return Err(CmdError::PipesHeldOpen);
     };
 
-    let out_buf = out_thread.join().unwrap();
-    let err_buf = err_thread.join().unwrap();
-
     let code = status.code().unwrap_or(1);
     trace!("Command exited with code {code}");
 
@@ -84,20 +275,205 @@ pub fn cmd(cmd: &[&str], env: HashMap<&str, &str>, cwd: &str) -> Result<String>
     trace!("Received output in stdout:\n{out}");
 
     if !err.is_empty() {
-        warn!("{err}");
-        return Err(CmdError::OutputInStderr).wrap_err("Output in stderr");
+        if let Some(e) = classify(&err) {
+            debug!("Classified stderr as {e}:\n{err}");
+            return Err(CmdError::Upstream(e));
+        }
+        return Err(CmdError::StderrOutput(err));
     }
 
     if out.trim().is_empty() {
-        warn!("No output in stdout");
-        return Err(CmdError::EmptyStdout).wrap_err("No output in stdout");
+        return Err(CmdError::EmptyStdout);
     }
 
     if code != 0 {
-        warn!("Exited with nonzero status: {code}");
-        return Err(CmdError::NonzeroStatus)
-            .wrap_err_with(|| format!("Exited with nonzero status: {code}"));
+        return Err(CmdError::NonzeroStatus(code));
     }
 
     Ok(out)
 }
+
+/// Read a pipe to the end on its own thread
+///
+/// The thread is detached, so a pipe that never closes only leaks the thread.
+fn read_pipe<R: Read + Send + 'static>(mut pipe: R) -> mpsc::Receiver<Vec<u8>> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = pipe.read_to_end(&mut buf);
+        let _ = tx.send(buf);
+    });
+    rx
+}
+
+/// # Wait up to a timeout for a child to exit, without reaping it
+///
+/// Until the child is reaped, its PID, and so the ID of the process group it leads, can't be
+/// reused, so the group can still be signalled safely.
+fn wait_exited(child: &Child, timeout: Duration) -> io::Result<bool> {
+    let start = Instant::now();
+    let mut delay = Duration::from_millis(1);
+
+    loop {
+        // SAFETY: siginfo_t is plain data, and waitid only writes to the one passed to it. WNOWAIT
+        // leaves the child waitable, so `Child::wait` still reaps it.
+        let exited = unsafe {
+            let mut info = std::mem::zeroed::<libc::siginfo_t>();
+            let flags = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+            if libc::waitid(libc::P_PID, child.id(), &raw mut info, flags) != 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            // with WNOHANG, nothing is written until the child has exited
+            info.si_pid() != 0
+        };
+
+        let elapsed = start.elapsed();
+        if exited || elapsed >= timeout {
+            return Ok(exited);
+        }
+        thread::sleep(delay.min(timeout.saturating_sub(elapsed)));
+        delay = (delay * 2).min(POLL_INTERVAL);
+    }
+}
+
+/// Terminate a child's process group with SIGTERM, then SIGKILL after a grace period
+fn terminate(child: &mut Child) -> io::Result<()> {
+    debug!("Terminating process group {}", child.id());
+    signal_group(child, libc::SIGTERM);
+    wait_exited(child, KILL_GRACE)?;
+
+    // the shell may exit on SIGTERM while its pipeline ignores it
+    signal_group(child, libc::SIGKILL);
+    child.wait()?;
+
+    Ok(())
+}
+
+/// Send a signal to every process in a child's process group
+///
+/// This must happen before the child is reaped, while the group ID still belongs to it.
+fn signal_group(child: &Child, signal: libc::c_int) {
+    let Ok(pgid) = libc::pid_t::try_from(child.id()) else {
+        return;
+    };
+
+    // SAFETY: kill(2) has no memory safety requirements, and the negative pid targets only the
+    // group the child leads. It fails harmlessly if the group no longer exists.
+    unsafe {
+        libc::kill(-pgid, signal);
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::utils::sandbox::SANDBOX;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn classify_builtin() {
+        let git = "fatal: unable to access 'https://gcc.gnu.org/git/gcc.git/': The requested URL returned error: 500";
+        assert_eq!(classify(git), Some(UpstreamError::Http(500)));
+
+        let curl = "curl: (22) The requested URL returned error: 429";
+        assert_eq!(classify(curl), Some(UpstreamError::RateLimited));
+
+        let curl = "curl: (6) Could not resolve host: ftp.gnu.org";
+        assert_eq!(classify(curl), Some(UpstreamError::Dns));
+
+        let curl = "curl: (7) Failed to connect to example.org port 443 after 0 ms: Couldn't connect to server";
+        assert_eq!(classify(curl), Some(UpstreamError::ConnectionRefused));
+
+        let jq = "jq: error (at <stdin>:0): Cannot iterate over null (null)";
+        assert_eq!(classify(jq), Some(UpstreamError::UnexpectedResponse));
+
+        assert_eq!(classify("something else entirely"), None);
+    }
+
+    #[test]
+    fn timeout_kills_pipeline() {
+        let start = std::time::Instant::now();
+        let res = cmd_with_timeout(
+            &["bash", "-c", "sleep 60 | cat"],
+            HashMap::new(),
+            ".",
+            None,
+            Duration::from_secs(1),
+        );
+
+        assert!(matches!(res, Err(CmdError::Timeout)));
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn timeout_kills_sandboxed_pipeline() {
+        let start = std::time::Instant::now();
+        let res = cmd_with_timeout(
+            &["bash", "-c", "sleep 60 | cat"],
+            HashMap::new(),
+            ".",
+            Some(&*SANDBOX),
+            Duration::from_secs(1),
+        );
+
+        assert!(matches!(res, Err(CmdError::Timeout)));
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn sandboxed_commands_in_sequence() {
+        // a SIGCHLD handler in vat would be inherited by the sandbox's forked init
+        for _ in 0..3 {
+            let res = cmd_with_timeout(
+                &["bash", "-c", "echo 1.0"],
+                HashMap::new(),
+                ".",
+                Some(&*SANDBOX),
+                Duration::from_secs(10),
+            );
+            assert_eq!(res.ok().as_deref(), Some("1.0\n"));
+        }
+    }
+
+    #[test]
+    fn background_holds_pipe() {
+        let start = std::time::Instant::now();
+        let res = cmd_with_timeout(
+            &["bash", "-c", "sleep 60 & echo 1.0"],
+            HashMap::new(),
+            ".",
+            None,
+            Duration::from_secs(30),
+        );
+
+        assert_eq!(res.ok().as_deref(), Some("1.0\n"));
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn held_pipe_isnt_timeout() {
+        // a process that leaves the group can't be killed with it, and keeps the pipes open
+        let pidfile = std::env::temp_dir().join(format!("vat-held-{}", std::process::id()));
+        let script = format!("setsid sleep 30 & echo $! > {}; sleep 0.5; echo 1.0", pidfile.display());
+        let res = cmd_with_timeout(
+            &["bash", "-c", &script],
+            HashMap::new(),
+            ".",
+            None,
+            Duration::from_secs(30),
+        );
+
+        // it outlives the command, so it's up to the test to kill it
+        let pid = std::fs::read_to_string(&pidfile).unwrap().trim().parse().unwrap();
+        let _ = std::fs::remove_file(&pidfile);
+        // SAFETY: kill(2) has no memory safety requirements
+        unsafe { libc::kill(pid, libc::SIGKILL) };
+
+        assert!(matches!(res, Err(CmdError::PipesHeldOpen)), "{res:?}");
+    }
+}
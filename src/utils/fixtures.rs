@@ -0,0 +1,163 @@
+// utils/fixtures.rs
+//! Recording upstream responses as fixtures, and replaying them for offline runs
+//!
+//! Fixtures are keyed by the SHA-256 of a request's method, URL, and body. `<key>.json` holds the
+//! request along with the response's status and headers, and `<key>.body` holds its body. Fetch
+//! commands inherit the mode through `VAT_RECORD` or `VAT_REPLAY`, so subcommands they call, like
+//! `vat ls-remote` and `vat http`, record and replay as well.
+
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use color_eyre::Result;
+use color_eyre::eyre::{Context, bail};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::http::Response;
+
+/// The fixtures for this run, if recording or replaying
+pub static FIXTURES: OnceLock<Fixtures> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Save every response
+    Record,
+
+    /// Answer requests only from saved responses
+    Replay,
+}
+
+impl Mode {
+    /// The variable passing the mode to fetch commands
+    pub const fn var(self) -> &'static str {
+        match self {
+            Self::Record => "VAT_RECORD",
+            Self::Replay => "VAT_REPLAY",
+        }
+    }
+}
+
+/// # A directory of fixtures
+#[derive(Debug)]
+pub struct Fixtures {
+    pub mode: Mode,
+    pub dir: PathBuf,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Fixture {
+    method: String,
+    url: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+}
+
+/// # Set up fixtures from `--record` or `--replay`, or the variables fetch commands inherit
+pub fn init(record: Option<&Path>, replay: Option<&Path>) -> Result<()> {
+    let from_env = |mode: Mode| env::var_os(mode.var()).map(PathBuf::from);
+    let (mode, dir) = match (record, replay) {
+        (Some(dir), _) => (Mode::Record, dir.to_path_buf()),
+        (None, Some(dir)) => (Mode::Replay, dir.to_path_buf()),
+        (None, None) => match (from_env(Mode::Record), from_env(Mode::Replay)) {
+            (Some(dir), _) => (Mode::Record, dir),
+            (None, Some(dir)) => (Mode::Replay, dir),
+            (None, None) => return Ok(()),
+        },
+    };
+
+    if mode == Mode::Record {
+        fs::create_dir_all(&dir)
+            .wrap_err_with(|| format!("Failed to create fixtures at '{}'", dir.display()))?;
+    } else if !dir.is_dir() {
+        bail!("No fixtures at '{}'", dir.display());
+    }
+
+    // fetch commands run from their package's directory
+    let dir = dir.canonicalize()?;
+    debug!("{mode:?}ing fixtures at {}", dir.display());
+
+    let _ = FIXTURES.set(Fixtures { mode, dir });
+    Ok(())
+}
+
+impl Fixtures {
+    fn path(&self, method: &str, url: &str, body: &[u8]) -> PathBuf {
+        let mut hasher = Sha256::new();
+        hasher.update(format!("{method}\n{url}\n"));
+        hasher.update(body);
+
+        let key = hasher.finalize().iter().fold(String::new(), |mut s, b| {
+            let _ = write!(s, "{b:02x}");
+            s
+        });
+        self.dir.join(key)
+    }
+
+    /// # The saved response to a request, if there is one
+    pub fn load(&self, method: &str, url: &str, body: &[u8]) -> Option<Response> {
+        let path = self.path(method, url, body);
+        let json = fs::read_to_string(path.with_extension("json")).ok()?;
+        let fixture = serde_json::from_str::<Fixture>(&json).ok()?;
+
+        Some(Response {
+            status: fixture.status,
+            headers: fixture.headers,
+            body: fs::read(path.with_extension("body")).ok()?,
+        })
+    }
+
+    /// # Save the response to a request
+    pub fn save(&self, method: &str, url: &str, body: &[u8], res: &Response) -> io::Result<()> {
+        let path = self.path(method, url, body);
+        let fixture = Fixture {
+            method: method.to_string(),
+            url: url.to_string(),
+            status: res.status,
+            headers: res.headers.clone(),
+        };
+
+        // the body goes first, so a fixture is never found without it
+        fs::write(path.with_extension("body"), &res.body)?;
+        fs::write(path.with_extension("json"), serde_json::to_vec_pretty(&fixture)?)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn round_trip() {
+        let dir = env::temp_dir().join(format!("vat-fixtures-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let fixtures = Fixtures {
+            mode: Mode::Record,
+            dir: dir.clone(),
+        };
+
+        let res = Response {
+            status: 200,
+            headers: vec![("etag".into(), "\"abc\"".into())],
+            body: b"0000".to_vec(),
+        };
+        fixtures.save("POST", "https://a/git-upload-pack", b"ls-refs", &res).unwrap();
+
+        let loaded = fixtures.load("POST", "https://a/git-upload-pack", b"ls-refs").unwrap();
+        assert_eq!(loaded.status, 200);
+        assert_eq!(loaded.header("ETag"), Some("\"abc\""));
+        assert_eq!(loaded.body, b"0000");
+
+        // the body is part of the key
+        assert!(fixtures.load("POST", "https://a/git-upload-pack", b"").is_none());
+        assert!(fixtures.load("GET", "https://a/git-upload-pack", b"ls-refs").is_none());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,199 @@
+// utils/http.rs
+
+use std::io;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use thiserror::Error;
+use ureq::Agent;
+
+use crate::utils::cache::CACHE;
+use crate::utils::fixtures::{FIXTURES, Mode};
+use crate::{CONFIG, NO_CACHE};
+
+/// Maximum size of a response body (64 MiB)
+const BODY_LIMIT: u64 = 64 * 1024 * 1024;
+
+static AGENT: LazyLock<Agent> = LazyLock::new(|| {
+    let timeout = CONFIG
+        .get()
+        .expect("Config should be initialized")
+        .fetch_timeout;
+
+    Agent::config_builder()
+        .timeout_global(Some(Duration::from_secs(timeout)))
+        .http_status_as_error(false)
+        .user_agent(concat!("vat/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .into()
+});
+
+#[derive(Error, Debug)]
+pub enum HttpError {
+    #[error("http status {0}")]
+    Status(u16),
+
+    #[error("host not found")]
+    HostNotFound,
+
+    #[error("timeout")]
+    Timeout,
+
+    #[error("tls error: {0}")]
+    Tls(String),
+
+    #[error("io error")]
+    Io(#[from] io::Error),
+
+    #[error("no fixture for {0}")]
+    NoFixture(String),
+
+    #[error("{0}")]
+    Other(String),
+}
+
+impl From<ureq::Error> for HttpError {
+    fn from(e: ureq::Error) -> Self {
+        match e {
+            ureq::Error::StatusCode(code) => Self::Status(code),
+            ureq::Error::HostNotFound => Self::HostNotFound,
+            ureq::Error::Timeout(_) => Self::Timeout,
+            ureq::Error::Io(e) => Self::Io(e),
+            ureq::Error::Tls(e) => Self::Tls(e.to_string()),
+            ureq::Error::Rustls(e) => Self::Tls(e.to_string()),
+            e => Self::Other(e.to_string()),
+        }
+    }
+}
+
+/// # A fully read HTTP response
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    /// Header names are lowercase
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// Returns the first value of a header, if present
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub const fn is_success(&self) -> bool {
+        self.status >= 200 && self.status < 300
+    }
+
+    /// Error out on any non-2xx status
+    pub fn error_for_status(self) -> Result<Self, HttpError> {
+        if self.is_success() {
+            Ok(self)
+        } else {
+            Err(HttpError::Status(self.status))
+        }
+    }
+
+    /// The status line and headers, like `curl -D` writes them
+    pub fn dump_headers(&self) -> String {
+        let headers = self
+            .headers
+            .iter()
+            .fold(String::new(), |s, (k, v)| s + k + ": " + v + "\r\n");
+        format!("HTTP/1.1 {}\r\n{headers}\r\n", self.status)
+    }
+}
+
+fn read(mut res: ureq::http::Response<ureq::Body>) -> Result<Response, HttpError> {
+    let status = res.status().as_u16();
+    let headers = res
+        .headers()
+        .iter()
+        .filter_map(|(k, v)| Some((k.as_str().to_string(), v.to_str().ok()?.to_string())))
+        .collect();
+
+    let body = res
+        .body_mut()
+        .with_config()
+        .limit(BODY_LIMIT)
+        .read_to_vec()?;
+
+    trace!("Received {} bytes with status {status}", body.len());
+    Ok(Response {
+        status,
+        headers,
+        body,
+    })
+}
+
+/// Headers that would let a server skip the body, which a fixture needs
+const CONDITIONAL: [&str; 2] = ["if-none-match", "if-modified-since"];
+
+/// # Send a request, or answer it from fixtures when recording or replaying
+fn send(
+    method: &str,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: Option<&[u8]>,
+) -> Result<Response, HttpError> {
+    trace!("{method} {url}");
+    let fixtures = FIXTURES.get();
+
+    if let Some(fixtures) = fixtures.filter(|f| f.mode == Mode::Replay) {
+        return fixtures
+            .load(method, url, body.unwrap_or_default())
+            .ok_or_else(|| HttpError::NoFixture(format!("{method} {url}")));
+    }
+
+    let headers = headers
+        .iter()
+        .filter(|(k, _)| fixtures.is_none() || !CONDITIONAL.contains(&&*k.to_lowercase()));
+
+    let res = if let Some(body) = body {
+        let mut req = AGENT.post(url);
+        for (k, v) in headers {
+            req = req.header(*k, *v);
+        }
+        read(req.send(body)?)?
+    } else {
+        let mut req = AGENT.get(url);
+        for (k, v) in headers {
+            req = req.header(*k, *v);
+        }
+        read(req.call()?)?
+    };
+
+    if let Some(fixtures) = fixtures
+        && let Err(e) = fixtures.save(method, url, body.unwrap_or_default(), &res)
+    {
+        warn!("Failed to record {method} {url}: {e}");
+    }
+    Ok(res)
+}
+
+/// # Send a GET request
+///
+/// Non-2xx statuses are not treated as errors here; see [`Response::error_for_status`].
+pub fn get(url: &str, headers: &[(&str, &str)]) -> Result<Response, HttpError> {
+    send("GET", url, headers, None)
+}
+
+/// # Send a GET request for a successful response's body, through the cache
+///
+/// Concurrent requests for the same kind and URL, like from channels sharing an upstream, are
+/// coalesced into one.
+pub fn get_cached(kind: &str, url: &str, headers: &[(&str, &str)]) -> Result<Vec<u8>, HttpError> {
+    CACHE.fetch_with(kind, url, *NO_CACHE, || {
+        let res = get(url, headers)?.error_for_status()?;
+        let headers = res.dump_headers();
+        Ok((res.body, Some(headers)))
+    })
+}
+
+/// # Send a POST request with a body
+pub fn post(url: &str, headers: &[(&str, &str)], body: &[u8]) -> Result<Response, HttpError> {
+    send("POST", url, headers, Some(body))
+}
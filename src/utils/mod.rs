@@ -1,5 +1,11 @@
+pub mod breaker;
+pub mod cache;
 pub mod cmd;
+pub mod fixtures;
 pub mod float;
+pub mod http;
 pub mod log;
+pub mod sandbox;
 pub mod str;
+pub mod throttle;
 pub mod ver;
@@ -0,0 +1,378 @@
+// utils/sandbox.rs
+//! Confinement for fetch commands
+//!
+//! Fetch commands come from community-contributed configs, so they run with a reduced
+//! environment, in new user, PID, and IPC namespaces, and with Landlock denying writes outside
+//! the cache and state. Namespaces and Landlock are each skipped with a warning if the kernel doesn't
+//! support them.
+
+use std::ffi::{CString, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::LazyLock;
+
+use crate::CONFIG;
+use crate::VAT_CACHE;
+use crate::VAT_STATE;
+use crate::utils::fixtures::{FIXTURES, Mode};
+
+/// The sandbox for fetch commands, as configured
+pub static SANDBOX: LazyLock<Sandbox> = LazyLock::new(|| {
+    let config = &CONFIG.get().expect("Config should be initialized").sandbox;
+
+    // vat's subcommands called by fetch commands keep state there, like stored etags
+    let mut writable = vec![VAT_CACHE.clone(), VAT_STATE.clone()];
+    writable.extend(config.writable.iter().cloned());
+    if let Some(fixtures) = FIXTURES.get().filter(|f| f.mode == Mode::Record) {
+        writable.push(fixtures.dir.clone());
+    }
+
+    Sandbox::new(&config.env, &writable, config.namespaces, config.landlock)
+});
+
+// filesystem access rights from linux/landlock.h
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_FS_MAKE_CHAR: u64 = 1 << 6;
+const ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+const ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+const ACCESS_FS_MAKE_FIFO: u64 = 1 << 10;
+const ACCESS_FS_MAKE_BLOCK: u64 = 1 << 11;
+const ACCESS_FS_MAKE_SYM: u64 = 1 << 12;
+const ACCESS_FS_REFER: u64 = 1 << 13;
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+const CREATE_RULESET_VERSION: u32 = 1 << 0;
+const RULE_PATH_BENEATH: libc::c_int = 1;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// A path writes are allowed beneath, with the rights that apply to it
+struct Rule {
+    path: CString,
+    access: u64,
+}
+
+/// # How fetch commands are confined
+pub struct Sandbox {
+    env: Vec<(String, OsString)>,
+    tmpdir: PathBuf,
+    namespaces: bool,
+    /// Handled write rights and the rules allowing them, if Landlock is used
+    landlock: Option<(u64, Vec<Rule>)>,
+}
+
+impl Sandbox {
+    fn new(env: &[String], writable: &[PathBuf], namespaces: bool, landlock: bool) -> Self {
+        // bash writes here-documents to TMPDIR, which has to be writable
+        let tmpdir = VAT_CACHE.join("tmp");
+        if let Err(e) = fs::create_dir_all(&tmpdir) {
+            warn!("Couldn't create '{}': {e}", tmpdir.display());
+        }
+
+        let env = env
+            .iter()
+            .filter_map(|k| Some((k.clone(), std::env::var_os(k)?)))
+            .collect();
+
+        let namespaces = namespaces && {
+            let supported = namespaces_supported();
+            if !supported {
+                warn!("Namespaces are unavailable, so fetches won't be isolated from each other");
+            }
+            supported
+        };
+
+        let landlock = if landlock {
+            let handled = landlock_abi().map(handled_access);
+            if handled.is_none() {
+                warn!("Landlock is unavailable, so fetches can write outside the cache");
+            }
+            handled.map(|handled| (handled, rules(writable, handled)))
+        } else {
+            None
+        };
+
+        Self {
+            env,
+            tmpdir,
+            namespaces,
+            landlock,
+        }
+    }
+
+    /// # Confine a command
+    ///
+    /// This clears the command's environment, so variables for it should be set afterwards. The
+    /// command is put in its own process group, which also holds everything it starts.
+    pub fn apply(&'static self, command: &mut Command) {
+        command
+            .env_clear()
+            .envs(self.env.iter().map(|(k, v)| (k, v)))
+            .env("TMPDIR", &self.tmpdir);
+
+        let namespaces = self.namespaces;
+        let landlock = self.landlock.as_ref();
+
+        // SAFETY: the hook runs in the forked child before exec, so it only makes system calls
+        // with data prepared beforehand, without allocating or taking locks
+        unsafe {
+            command.pre_exec(move || {
+                if libc::setpgid(0, 0) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                if namespaces {
+                    enter_namespaces()?;
+                }
+                if let Some((handled, rules)) = landlock {
+                    restrict(*handled, rules)?;
+                }
+                Ok(())
+            });
+        }
+    }
+}
+
+/// Write rights handled for a Landlock ABI version, all of which are denied outside the rules
+const fn handled_access(abi: i32) -> u64 {
+    let mut handled = ACCESS_FS_WRITE_FILE
+        | ACCESS_FS_REMOVE_DIR
+        | ACCESS_FS_REMOVE_FILE
+        | ACCESS_FS_MAKE_CHAR
+        | ACCESS_FS_MAKE_DIR
+        | ACCESS_FS_MAKE_REG
+        | ACCESS_FS_MAKE_SOCK
+        | ACCESS_FS_MAKE_FIFO
+        | ACCESS_FS_MAKE_BLOCK
+        | ACCESS_FS_MAKE_SYM;
+    if abi >= 2 {
+        handled |= ACCESS_FS_REFER;
+    }
+    if abi >= 3 {
+        handled |= ACCESS_FS_TRUNCATE;
+    }
+    handled
+}
+
+/// Rules for the writable paths, and `/dev/null`, which shells redirect to all the time
+fn rules(writable: &[PathBuf], handled: u64) -> Vec<Rule> {
+    writable
+        .iter()
+        .map(PathBuf::as_path)
+        .chain([Path::new("/dev/null")])
+        .filter_map(|path| {
+            let metadata = fs::metadata(path).ok()?;
+
+            // rules for files may only allow rights that apply to files
+            let access = if metadata.is_dir() {
+                handled
+            } else {
+                handled & (ACCESS_FS_WRITE_FILE | ACCESS_FS_TRUNCATE)
+            };
+
+            Some(Rule {
+                path: CString::new(path.as_os_str().as_bytes()).ok()?,
+                access,
+            })
+        })
+        .collect()
+}
+
+/// The supported Landlock ABI version, if any
+fn landlock_abi() -> Option<i32> {
+    // SAFETY: with a null attribute and the version flag, this only queries the ABI version
+    let abi = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            CREATE_RULESET_VERSION,
+        )
+    };
+    i32::try_from(abi).ok().filter(|abi| *abi > 0)
+}
+
+/// Whether unprivileged namespaces can be created, by trying in a throwaway process
+fn namespaces_supported() -> bool {
+    let mut command = Command::new("true");
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+
+    // SAFETY: as in `Sandbox::apply`
+    unsafe {
+        command.pre_exec(enter_namespaces);
+    }
+    command.status().is_ok_and(|s| s.success())
+}
+
+/// Enter new user, PID, and IPC namespaces
+///
+/// Only children join a new PID namespace, so this forks, and the original process waits for the
+/// child and exits with its status. The child is the namespace's init, so if the original process
+/// is killed, killing the child takes down everything in the namespace.
+fn enter_namespaces() -> io::Result<()> {
+    // SAFETY: unshare, signal, fork, prctl, close, waitpid, and _exit are async-signal-safe, and
+    // the child is single-threaded, as unsharing a user namespace requires
+    unsafe {
+        if libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWPID | libc::CLONE_NEWIPC) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // this process never calls exec, so it would still run handlers inherited from the
+        // spawning process, like one noticing children exiting
+        libc::signal(libc::SIGCHLD, libc::SIG_DFL);
+
+        let pid = libc::fork();
+        if pid < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if pid == 0 {
+            if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            return Ok(());
+        }
+
+        // the spawning process waits for a close-on-exec pipe to close, which this process would
+        // otherwise hold open along with the output pipes, since it never calls exec
+        if libc::syscall(libc::SYS_close_range, 0, libc::c_uint::MAX, 0) != 0 {
+            for fd in 0..1024 {
+                libc::close(fd);
+            }
+        }
+
+        let mut status = 0;
+        while libc::waitpid(pid, &raw mut status, 0) < 0 {
+            if io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+                libc::_exit(1);
+            }
+        }
+
+        if libc::WIFEXITED(status) {
+            libc::_exit(libc::WEXITSTATUS(status));
+        }
+        libc::_exit(128 + libc::WTERMSIG(status));
+    }
+}
+
+/// Deny writes outside the rules with Landlock
+fn restrict(handled: u64, rules: &[Rule]) -> io::Result<()> {
+    let attr = RulesetAttr {
+        handled_access_fs: handled,
+    };
+
+    // SAFETY: the attributes match linux/landlock.h, the paths are nul-terminated, and every
+    // opened file descriptor is closed
+    unsafe {
+        if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let ruleset = libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &raw const attr,
+            size_of::<RulesetAttr>(),
+            0,
+        );
+        let Ok(ruleset) = libc::c_int::try_from(ruleset) else {
+            return Err(io::Error::last_os_error());
+        };
+        if ruleset < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        for rule in rules {
+            let fd = libc::open(rule.path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC);
+            if fd < 0 {
+                continue;
+            }
+
+            let beneath = PathBeneathAttr {
+                allowed_access: rule.access,
+                parent_fd: fd,
+            };
+            let res = libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset,
+                RULE_PATH_BENEATH,
+                &raw const beneath,
+                0,
+            );
+            libc::close(fd);
+            if res != 0 {
+                libc::close(ruleset);
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        let res = libc::syscall(libc::SYS_landlock_restrict_self, ruleset, 0);
+        libc::close(ruleset);
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn confines_commands() {
+        let dir = std::env::temp_dir().join(format!("vat-sandbox-{}", std::process::id()));
+        let outside = std::env::temp_dir().join(format!("vat-sandbox-{}-out", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let sandbox: &'static Sandbox = Box::leak(Box::new(Sandbox::new(
+            &["PATH".into()],
+            std::slice::from_ref(&dir),
+            true,
+            true,
+        )));
+
+        let script = format!(
+            "echo ok > {inside}; echo no > {outside} 2>/dev/null; echo \"${{CARGO_MANIFEST_DIR-unset}} $$\"",
+            inside = dir.join("file").display(),
+            outside = outside.display(),
+        );
+        let mut command = Command::new("bash");
+        command.args(["-c", &script]);
+        sandbox.apply(&mut command);
+        let out = command.output().unwrap();
+        let stdout = String::from_utf8_lossy(&out.stdout);
+        let (env, pid) = stdout.trim().split_once(' ').unwrap();
+
+        assert_eq!(env, "unset");
+        assert_eq!(fs::read_to_string(dir.join("file")).unwrap(), "ok\n");
+        if sandbox.namespaces {
+            assert_eq!(pid, "1");
+        }
+        if sandbox.landlock.is_some() {
+            assert!(!outside.exists());
+        }
+
+        let _ = fs::remove_dir_all(&dir);
+        let _ = fs::remove_file(&outside);
+    }
+}
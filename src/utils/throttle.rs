@@ -0,0 +1,302 @@
+// utils/throttle.rs
+//! Per-host concurrency limits and rate limiting
+//!
+//! Each host gets a cap on concurrent fetches and a token bucket, which refills at `rate` tokens
+//! per second up to `burst`. A fetch needs a free slot and a whole token. A configured host shares
+//! its slots and bucket with its subdomains, while other hosts each get their own.
+
+use std::collections::HashMap;
+use std::sync::{Condvar, LazyLock, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::CONFIG;
+use crate::config;
+
+/// The limits for fetches, as configured
+pub static THROTTLE: LazyLock<Throttle> = LazyLock::new(|| {
+    let config = &CONFIG.get().expect("Config should be initialized").throttle;
+    Throttle::new(config)
+});
+
+/// # Why a host isn't ready for another fetch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Busy {
+    /// Every slot is taken, until a fetch finishes
+    Full,
+
+    /// The bucket is empty, until it refills after the duration
+    Limited(Duration),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Limit {
+    concurrency: usize,
+    rate: f64,
+    burst: f64,
+}
+
+#[derive(Debug)]
+struct Host {
+    active: usize,
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// # Limits on fetches per host
+pub struct Throttle {
+    default: Limit,
+    limits: Vec<(String, Limit)>,
+    /// State by bucket, which is the configured host a host matched, or the host itself
+    hosts: Mutex<HashMap<String, Host>>,
+    released: Condvar,
+}
+
+/// # A fetch in progress, which frees its host's slot when dropped
+pub struct Permit<'a> {
+    throttle: &'a Throttle,
+    bucket: String,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if let Some(host) = self.throttle.lock().get_mut(&self.bucket) {
+            host.active = host.active.saturating_sub(1);
+        }
+        self.throttle.released.notify_all();
+    }
+}
+
+impl Throttle {
+    pub fn new(config: &config::Throttle) -> Self {
+        let default = Limit {
+            concurrency: config.concurrency,
+            rate: config.rate,
+            burst: config.burst,
+        };
+
+        let limits = config
+            .hosts
+            .iter()
+            .map(|h| {
+                let limit = Limit {
+                    concurrency: h.concurrency.unwrap_or(default.concurrency),
+                    rate: h.rate.unwrap_or(default.rate),
+                    burst: h.burst.unwrap_or(default.burst),
+                };
+                (h.host.to_lowercase(), limit)
+            })
+            .collect();
+
+        Self {
+            default,
+            limits,
+            hosts: Mutex::new(HashMap::new()),
+            released: Condvar::new(),
+        }
+    }
+
+    /// The bucket for a host and its limit, from the most specific matching entry
+    fn bucket(&self, host: &str) -> (String, Limit) {
+        self.limits
+            .iter()
+            .filter(|(h, _)| {
+                host == h
+                    || host
+                        .strip_suffix(h.as_str())
+                        .is_some_and(|sub| sub.ends_with('.'))
+            })
+            .max_by_key(|(h, _)| h.len())
+            .map_or_else(|| (host.to_string(), self.default), |(h, limit)| (h.clone(), *limit))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Host>> {
+        // the state stays consistent even if a holder panicked, since updates are single writes
+        self.hosts.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Refill a host's bucket and check whether it can take another fetch
+    fn check(&self, hosts: &mut HashMap<String, Host>, host: &str) -> Result<(), Busy> {
+        let (bucket, limit) = self.bucket(host);
+        let now = Instant::now();
+        let state = hosts.entry(bucket).or_insert_with(|| Host {
+            active: 0,
+            tokens: limit.burst.max(1.0),
+            refilled: now,
+        });
+
+        if limit.rate > 0.0 {
+            let elapsed = now.duration_since(state.refilled).as_secs_f64();
+            state.tokens = limit
+                .burst
+                .max(1.0)
+                .min(elapsed.mul_add(limit.rate, state.tokens));
+            state.refilled = now;
+        }
+
+        if limit.concurrency > 0 && state.active >= limit.concurrency {
+            return Err(Busy::Full);
+        }
+        if limit.rate > 0.0 && state.tokens < 1.0 {
+            let wait = (1.0 - state.tokens) / limit.rate;
+            return Err(Busy::Limited(Duration::from_secs_f64(wait)));
+        }
+        Ok(())
+    }
+
+    /// # Check whether a host can take another fetch right now
+    pub fn ready(&self, host: &str) -> Result<(), Busy> {
+        self.check(&mut self.lock(), host)
+    }
+
+    /// # Wait until a host can take another fetch, then take a slot and a token
+    pub fn acquire(&self, host: &str) -> Permit<'_> {
+        let mut hosts = self.lock();
+
+        loop {
+            let busy = match self.check(&mut hosts, host) {
+                Ok(()) => break,
+                Err(busy) => busy,
+            };
+
+            trace!("Waiting on {host}: {busy:?}");
+            hosts = match busy {
+                Busy::Full => self
+                    .released
+                    .wait(hosts)
+                    .unwrap_or_else(PoisonError::into_inner),
+                Busy::Limited(wait) => {
+                    let waited = self.released.wait_timeout(hosts, wait);
+                    waited.unwrap_or_else(PoisonError::into_inner).0
+                }
+            };
+        }
+
+        let (bucket, limit) = self.bucket(host);
+        if let Some(state) = hosts.get_mut(&bucket) {
+            state.active += 1;
+            if limit.rate > 0.0 {
+                state.tokens -= 1.0;
+            }
+        }
+        drop(hosts);
+
+        Permit {
+            throttle: self,
+            bucket,
+        }
+    }
+
+    /// # Wait for a fetch to finish, or at most `timeout`
+    pub fn wait(&self, timeout: Duration) {
+        let hosts = self.lock();
+        let _ = self.released.wait_timeout(hosts, timeout);
+    }
+}
+
+/// # The host an upstream points to, like "github.com" for `https://github.com/a/b`
+///
+/// This understands URLs and scp-like git remotes such as "git@host:path".
+pub fn host(upstream: &str) -> Option<String> {
+    let authority = match upstream.split_once("://") {
+        Some((_, rest)) => rest.split(['/', '?', '#']).next()?,
+        // scp-like remotes need a user, to tell them apart from paths with colons
+        None => upstream.split_once('@')?.1.split(':').next()?,
+    };
+
+    let host = authority.rsplit('@').next()?;
+    let host = match host.strip_prefix('[') {
+        // bracketed ipv6 addresses contain colons
+        Some(v6) => v6.split(']').next()?,
+        None => host.split(':').next()?,
+    };
+
+    (!host.is_empty()).then(|| host.to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn throttle() -> Throttle {
+        Throttle::new(&config::Throttle {
+            concurrency: 2,
+            rate: 0.0,
+            burst: 1.0,
+            hosts: vec![config::HostThrottle {
+                host: "example.org".into(),
+                concurrency: Some(1),
+                rate: Some(10.0),
+                burst: Some(2.0),
+            }],
+        })
+    }
+
+    #[test]
+    fn hosts() {
+        assert_eq!(
+            host("https://GitHub.com/a/b").as_deref(),
+            Some("github.com")
+        );
+        assert_eq!(
+            host("ftp://ftp.gnu.org:21/gnu/").as_deref(),
+            Some("ftp.gnu.org")
+        );
+        assert_eq!(host("ssh://git@host.xz/repo").as_deref(), Some("host.xz"));
+        assert_eq!(
+            host("git@codeberg.org:a/b.git").as_deref(),
+            Some("codeberg.org")
+        );
+        assert_eq!(host("http://[::1]:8080/x").as_deref(), Some("::1"));
+        assert_eq!(host(""), None);
+        assert_eq!(host("/some/path"), None);
+    }
+
+    #[test]
+    fn concurrency() {
+        let t = throttle();
+        let a = t.acquire("other.net");
+        let _b = t.acquire("other.net");
+        assert_eq!(t.ready("other.net"), Err(Busy::Full));
+        assert_eq!(t.ready("another.net"), Ok(()));
+
+        drop(a);
+        assert_eq!(t.ready("other.net"), Ok(()));
+    }
+
+    #[test]
+    fn rate() {
+        let t = throttle();
+
+        // subdomains share the limit of their domain
+        drop(t.acquire("ftp.example.org"));
+        drop(t.acquire("ftp.example.org"));
+        assert!(matches!(t.ready("ftp.example.org"), Err(Busy::Limited(_))));
+        assert_eq!(t.ready("notexample.org"), Ok(()));
+
+        let start = Instant::now();
+        drop(t.acquire("ftp.example.org"));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn shared_by_subdomains() {
+        let t = throttle();
+
+        // sibling subdomains take from the same bucket and slots
+        drop(t.acquire("ftp.example.org"));
+        drop(t.acquire("www.example.org"));
+        assert!(matches!(t.ready("example.org"), Err(Busy::Limited(_))));
+        assert!(matches!(t.ready("mirror.example.org"), Err(Busy::Limited(_))));
+
+        std::thread::sleep(Duration::from_millis(150));
+        let _a = t.acquire("ftp.example.org");
+        assert_eq!(t.ready("www.example.org"), Err(Busy::Full));
+
+        // unconfigured hosts still have their own
+        let _b = t.acquire("a.other.net");
+        let _c = t.acquire("a.other.net");
+        assert_eq!(t.ready("a.other.net"), Err(Busy::Full));
+        assert_eq!(t.ready("b.other.net"), Ok(()));
+    }
+}
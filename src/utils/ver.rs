@@ -1,8 +1,22 @@
 // utils/ver.rs
 
+use std::cmp::Ordering;
+use std::iter::Peekable;
+use std::str::Chars;
+use std::sync::LazyLock;
+
+use regex::Regex;
+
 use crate::package::Package;
 use crate::utils::str::basename;
 
+static UNDERSCORE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"([0-9]+)_([0-9]+)").expect("Regex should be valid"));
+static RELEASE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)^release[-_]").expect("Regex should be valid"));
+static PREFIX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[vrn]([0-9])").expect("Regex should be valid"));
+
 #[derive(Debug, Default)]
 pub struct Version {
     pub raw: String,
@@ -21,8 +35,7 @@ impl Version {
         let ver = self
             .raw
             .lines()
-            .filter(|l| !l.is_empty())
-            .next_back()
+            .rfind(|l| !l.is_empty())
             .map_or_else(|| unreachable!("No output"), str::to_lowercase);
 
         let name = basename(&package.name);
@@ -35,3 +48,338 @@ impl Version {
         self.fmt = ver.trim().to_string();
     }
 }
+
+/// # Pre-release tags, from least to most mature
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum PreTag {
+    Dev,
+    Alpha,
+    Beta,
+    Preview,
+    Rc,
+}
+
+impl PreTag {
+    /// Recognize a pre-release tag
+    ///
+    /// The single letter forms are only recognized when a number follows, since "2.2a" is more
+    /// likely a patch release than an alpha.
+    fn from_word(word: &str, numbered: bool) -> Option<Self> {
+        match word {
+            "dev" | "snapshot" => Some(Self::Dev),
+            "alpha" => Some(Self::Alpha),
+            "beta" => Some(Self::Beta),
+            "pre" | "preview" => Some(Self::Preview),
+            "rc" => Some(Self::Rc),
+            "a" if numbered => Some(Self::Alpha),
+            "b" if numbered => Some(Self::Beta),
+            "c" if numbered => Some(Self::Rc),
+            _ => None,
+        }
+    }
+}
+
+/// # A single component of a parsed version
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Component {
+    /// A numeric part, stored as digits without leading zeros
+    Num(String),
+    /// A pre-release tag with an optional number, like "rc1" or "beta.2"
+    Pre(PreTag, u64),
+    /// A letter directly following a number, like the "a" in "3.6a"
+    Letter(char),
+    /// A post-release tag, like "p1" or "post2"
+    Post(u64),
+    /// Anything else
+    Word(String),
+}
+
+impl Component {
+    /// Components of different kinds at the same position are ordered like this:
+    ///
+    /// pre-release < unknown word < end of version (or 0) < letter < post-release < number
+    const fn rank(&self) -> u8 {
+        match self {
+            Self::Pre(..) => 0,
+            Self::Word(_) => 1,
+            Self::Num(n) if n.is_empty() => 2,
+            Self::Letter(_) => 3,
+            Self::Post(_) => 4,
+            Self::Num(_) => 5,
+        }
+    }
+
+    fn cmp_opt(a: Option<&Self>, b: Option<&Self>) -> Ordering {
+        static ZERO: Component = Component::Num(String::new());
+        let a = a.unwrap_or(&ZERO);
+        let b = b.unwrap_or(&ZERO);
+
+        match (a, b) {
+            (Self::Num(a), Self::Num(b)) => a.len().cmp(&b.len()).then_with(|| a.cmp(b)),
+            (Self::Pre(a, an), Self::Pre(b, bn)) => a.cmp(b).then(an.cmp(bn)),
+            (Self::Letter(a), Self::Letter(b)) => a.cmp(b),
+            (Self::Post(a), Self::Post(b)) => a.cmp(b),
+            (Self::Word(a), Self::Word(b)) => a.cmp(b),
+            (a, b) => a.rank().cmp(&b.rank()),
+        }
+    }
+}
+
+/// # A version parsed for ordering
+///
+/// Versions are split into numeric parts, pre-release tags (dev, alpha, beta, pre, rc), letter
+/// suffixes and post-release tags. Separators (`.`, `-`, `_`, `+`, `~`) are insignificant, as are
+/// trailing zeros, so "1.0" and "1.0.0" compare equal. Date-style versions like "20240102" or
+/// "2024-01-02" are split into year, month and day so they compare with dotted dates.
+#[derive(Debug, Clone)]
+pub struct ParsedVersion {
+    components: Vec<Component>,
+}
+
+impl ParsedVersion {
+    /// Whether the version has a pre-release tag, like "1.0-rc1"
+    pub fn is_prerelease(&self) -> bool {
+        self.components.iter().any(|c| matches!(c, Component::Pre(..)))
+    }
+
+    pub fn parse(s: &str) -> Self {
+        let s = s.trim().to_lowercase();
+        let s = s
+            .strip_prefix('v')
+            .filter(|s| s.starts_with(|c: char| c.is_ascii_digit()))
+            .unwrap_or(&s);
+
+        let mut components = vec![];
+        let mut chars = s.chars().peekable();
+        let mut after_num = false;
+
+        while let Some(&c) = chars.peek() {
+            if c.is_ascii_digit() {
+                let mut digits = String::new();
+                while let Some(d) = chars.next_if(char::is_ascii_digit) {
+                    digits.push(d);
+                }
+
+                components.extend(split_date(&digits).into_iter().map(Component::Num));
+                after_num = true;
+            } else if c.is_alphabetic() {
+                let mut word = String::new();
+                while let Some(l) = chars.next_if(|c| c.is_alphabetic()) {
+                    word.push(l);
+                }
+
+                let numbered = chars.peek().is_some_and(char::is_ascii_digit);
+                let mut lookahead = chars.clone();
+                let separated_num = lookahead.next().is_some_and(is_separator)
+                    && lookahead.peek().is_some_and(char::is_ascii_digit);
+
+                // a tag's number may be split off by a separator, like "beta.2"
+                let take_num = |chars: &mut Peekable<Chars>| {
+                    if !numbered && separated_num {
+                        chars.next();
+                    }
+                    let mut n = String::new();
+                    while let Some(d) = chars.next_if(char::is_ascii_digit) {
+                        n.push(d);
+                    }
+                    if n.is_empty() {
+                        0
+                    } else {
+                        n.parse::<u64>().unwrap_or(u64::MAX)
+                    }
+                };
+
+                let component = if let Some(pre) = PreTag::from_word(&word, numbered) {
+                    Component::Pre(pre, take_num(&mut chars))
+                } else if matches!(word.as_str(), "post" | "p" | "pl" | "patch")
+                    && (numbered || separated_num)
+                {
+                    Component::Post(take_num(&mut chars))
+                } else if after_num && word.len() == 1 && !numbered {
+                    Component::Letter(word.chars().next().unwrap_or_default())
+                } else if matches!(word.as_str(), "final" | "stable") {
+                    continue;
+                } else {
+                    Component::Word(word)
+                };
+
+                components.push(component);
+                after_num = false;
+            } else {
+                chars.next();
+                after_num = false;
+            }
+        }
+
+        Self { components }
+    }
+}
+
+const fn is_separator(c: char) -> bool {
+    matches!(c, '.' | '-' | '_' | '+' | '~')
+}
+
+/// Split "YYYYMMDD" into year, month and day, and strip leading zeros from anything else
+fn split_date(digits: &str) -> Vec<String> {
+    let strip = |s: &str| s.trim_start_matches('0').to_string();
+
+    if digits.len() == 8
+        && (digits.starts_with("19") || digits.starts_with("20"))
+        && matches!(
+            &digits[4..6],
+            "01" | "02" | "03" | "04" | "05" | "06" | "07" | "08" | "09" | "10" | "11" | "12"
+        )
+        && digits[6..8]
+            .parse::<u8>()
+            .is_ok_and(|d| (1..=31).contains(&d))
+    {
+        return vec![
+            strip(&digits[..4]),
+            strip(&digits[4..6]),
+            strip(&digits[6..]),
+        ];
+    }
+
+    vec![strip(digits)]
+}
+
+impl Ord for ParsedVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let len = self.components.len().max(other.components.len());
+
+        (0..len)
+            .map(|i| Component::cmp_opt(self.components.get(i), other.components.get(i)))
+            .find(|o| o.is_ne())
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+impl PartialEq for ParsedVersion {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for ParsedVersion {}
+
+impl PartialOrd for ParsedVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// # Trim a version like `vtrim`
+///
+/// This trims the name with an optional "-" or "_", a "release" prefix, and a "v", "r", or "n"
+/// prefix, and replaces underscores between numbers with dots.
+pub fn vtrim(line: &str, name: &str) -> String {
+    let line = line
+        .get(..name.len())
+        .filter(|prefix| prefix.eq_ignore_ascii_case(name))
+        .map_or(line, |_| {
+            let rest = &line[name.len()..];
+            rest.strip_prefix(['-', '_']).unwrap_or(rest)
+        });
+    let line = RELEASE.replace(line, "");
+    let mut line = PREFIX.replace(&line, "$1").into_owned();
+
+    // replace repeatedly, since matches can't overlap, like in "1_2_3"
+    while UNDERSCORE.is_match(&line) {
+        line = UNDERSCORE.replace_all(&line, "$1.$2").into_owned();
+    }
+    line
+}
+
+/// # Compare two version strings
+pub fn compare(a: &str, b: &str) -> Ordering {
+    ParsedVersion::parse(a).cmp(&ParsedVersion::parse(b))
+}
+
+/// # Sort version strings in ascending order
+///
+/// Versions that compare equal are ordered by their raw strings, so the result is deterministic.
+pub fn sort<S: AsRef<str>>(versions: &mut [S]) {
+    versions.sort_by_cached_key(|v| (ParsedVersion::parse(v.as_ref()), v.as_ref().to_string()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn sorted(versions: &[&str]) -> Vec<String> {
+        let mut v = versions.iter().map(ToString::to_string).collect::<Vec<_>>();
+        sort(&mut v);
+        v
+    }
+
+    #[test]
+    fn numeric() {
+        assert_eq!(
+            sorted(&["1.10", "1.9", "1.9.1", "2", "1.0"]),
+            ["1.0", "1.9", "1.9.1", "1.10", "2"]
+        );
+        assert_eq!(compare("1.0", "1.0.0"), Ordering::Equal);
+        assert_eq!(compare("1.01", "1.1"), Ordering::Equal);
+        assert_eq!(
+            compare("99999999999999999999999", "100000000000000000000000"),
+            Ordering::Less
+        );
+    }
+
+    #[test]
+    fn prerelease() {
+        assert_eq!(
+            sorted(&[
+                "1.0",
+                "1.0rc1",
+                "1.0-beta.2",
+                "1.0-alpha",
+                "1.0-rc.2",
+                "1.0b1",
+                "0.9",
+                "1.0dev1",
+                "1.0.1"
+            ]),
+            [
+                "0.9",
+                "1.0dev1",
+                "1.0-alpha",
+                "1.0b1",
+                "1.0-beta.2",
+                "1.0rc1",
+                "1.0-rc.2",
+                "1.0",
+                "1.0.1"
+            ]
+        );
+        assert_eq!(compare("1.0-rc1", "1.0-rc.1"), Ordering::Equal);
+        assert_eq!(compare("1.0pre1", "1.0rc1"), Ordering::Less);
+    }
+
+    #[test]
+    fn letter_suffix() {
+        // previously misordered as alphas
+        assert_eq!(
+            sorted(&["2.2a", "2.2", "2.1c", "2.3", "2.2b"]),
+            ["2.1c", "2.2", "2.2a", "2.2b", "2.3"]
+        );
+        assert_eq!(compare("3.6a", "3.6.1"), Ordering::Less);
+        assert_eq!(compare("9.8p1", "9.8"), Ordering::Greater);
+        assert_eq!(compare("9.8p1", "9.9p1"), Ordering::Less);
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(compare("20240102", "2024.01.02"), Ordering::Equal);
+        assert_eq!(compare("2024-01-02", "20231231"), Ordering::Greater);
+        assert_eq!(compare("2023.12.31", "20240101"), Ordering::Less);
+    }
+
+    #[test]
+    fn words() {
+        assert_eq!(compare("v1.2", "1.2"), Ordering::Equal);
+        assert_eq!(compare("1.2-final", "1.2"), Ordering::Equal);
+        assert_eq!(sorted(&["1.0", "merge", "0.1"]), ["merge", "0.1", "1.0"]);
+    }
+}